@@ -1,66 +1,97 @@
-use crate::driver::{Driver, Request, StateRef};
-use crate::run::Run;
+use crate::completions::{Completions, Opt, Shell, Values, SHELLS};
+use crate::config::{self, GlobalConfig};
+use crate::driver::{self, Driver, Request, StateRef, Target};
+use crate::error::Error;
+use crate::exec::{Event, Output, TermReporter};
+use crate::graph::{Graph, GraphOptions};
+use crate::recipe::Recipe;
+use crate::report::Report;
+use crate::run::{self, Run};
+use crate::sweep::Sweep;
+use crate::workdir;
 use anyhow::{anyhow, bail};
-use argh::FromArgs;
+use argh::{ArgsInfo, FlagInfoKind, FromArgs, Optionality};
 use camino::{Utf8Path, Utf8PathBuf};
 use std::fmt::Display;
+use std::io::{IsTerminal, Read, Write};
+use std::process::ExitCode;
 use std::str::FromStr;
+use std::time::Duration;
 
+#[derive(Clone, Copy, PartialEq)]
 enum Mode {
     EmitNinja,
     ShowPlan,
+    ShowPlanJson,
     ShowDot,
+    ShowGraph,
+    ShowMermaid,
+    Completions,
+    Clean,
     Generate,
     Run,
+    Sweep,
+    Recipe,
+    Replay,
 }
 
+/// Each mode's name on the command line.
+const MODES: &[(Mode, &str)] = &[
+    (Mode::Run, "run"),
+    (Mode::Sweep, "sweep"),
+    (Mode::Recipe, "recipe"),
+    (Mode::Replay, "replay"),
+    (Mode::ShowPlan, "plan"),
+    (Mode::ShowPlanJson, "plan-json"),
+    (Mode::EmitNinja, "emit"),
+    (Mode::Generate, "gen"),
+    (Mode::ShowDot, "dot"),
+    (Mode::ShowGraph, "graph"),
+    (Mode::ShowMermaid, "mermaid"),
+    (Mode::Completions, "completions"),
+    (Mode::Clean, "clean"),
+];
+
 impl FromStr for Mode {
     type Err = String;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s {
-            "emit" => Ok(Mode::EmitNinja),
-            "plan" => Ok(Mode::ShowPlan),
-            "gen" => Ok(Mode::Generate),
-            "run" => Ok(Mode::Run),
-            "dot" => Ok(Mode::ShowDot),
-            _ => Err("unknown mode".to_string()),
-        }
+        MODES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(mode, _)| *mode)
+            .ok_or("unknown mode".to_string())
     }
 }
 
 impl Display for Mode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Mode::EmitNinja => write!(f, "emit"),
-            Mode::ShowPlan => write!(f, "plan"),
-            Mode::Generate => write!(f, "gen"),
-            Mode::Run => write!(f, "run"),
-            Mode::ShowDot => write!(f, "dot"),
-        }
+        let (_, name) = MODES.iter().find(|(mode, _)| mode == self).unwrap();
+        write!(f, "{}", name)
     }
 }
 
-#[derive(FromArgs)]
+#[derive(FromArgs, ArgsInfo)]
 /// A generic compiler driver.
 struct FakeArgs {
-    /// the input file
+    /// the input file, or - for stdin
     #[argh(positional)]
     input: Option<Utf8PathBuf>,
 
-    /// the output file
+    /// an output file, once for each --to state
     #[argh(option, short = 'o')]
-    output: Option<Utf8PathBuf>,
+    output: Vec<Utf8PathBuf>,
 
     /// the state to start from
     #[argh(option)]
     from: Option<String>,
 
-    /// the state to produce
+    /// a state to produce, possibly several
     #[argh(option)]
-    to: Option<String>,
+    to: Vec<String>,
 
-    /// execution mode (run, plan, emit, gen, dot)
+    /// execution mode (run, sweep, recipe, replay, plan, plan-json, emit, gen, dot, graph, mermaid,
+    /// completions, clean)
     #[argh(option, short = 'm', default = "Mode::Run")]
     mode: Mode,
 
@@ -72,53 +103,362 @@ struct FakeArgs {
     #[argh(switch)]
     keep: Option<bool>,
 
+    /// in clean mode, only remove directories unused for this long (like 12h or 7d)
+    #[argh(option, from_str_fn(parse_age))]
+    older_than: Option<Duration>,
+
+    /// in run mode, print the output to stdout while it is being written
+    #[argh(switch)]
+    stream: Option<bool>,
+
+    /// in run or replay mode, build in a temporary directory instead of the input's persistent one
+    #[argh(switch)]
+    fresh: bool,
+
+    /// in run mode, rebuild whenever an input changes
+    #[argh(switch)]
+    watch: bool,
+
+    /// in run mode, show a progress bar for each step instead of the build's output
+    #[argh(switch)]
+    progress: bool,
+
+    /// in run mode, print the time taken by each step
+    #[argh(switch)]
+    timing: bool,
+
+    /// in run or sweep mode, write a JSON report with each step's or value's results to a file
+    #[argh(option)]
+    report: Option<Utf8PathBuf>,
+
+    /// in run mode, the number of jobs to run in parallel
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+
+    /// in run mode, keep going after a command fails
+    #[argh(switch)]
+    keep_going: Option<bool>,
+
+    /// in run mode, show what would run without running it
+    #[argh(switch, short = 'n')]
+    dry_run: Option<bool>,
+
+    /// in run mode, explain why each command runs
+    #[argh(switch)]
+    explain: Option<bool>,
+
+    /// in run mode, an extra argument to pass to ninja
+    #[argh(option)]
+    ninja_arg: Vec<String>,
+
     /// set a configuration variable (key=value)
     #[argh(option, short = 's')]
     set: Vec<String>,
 
+    /// in sweep mode, a configuration key and the values to try (key=v1,v2 or key=@file)
+    #[argh(option)]
+    sweep: Option<String>,
+
+    /// in recipe mode, the file to write the build recipe to (instead of stdout); in run mode, a
+    /// file to record the plan in as it runs; in replay mode, the recipe to execute
+    #[argh(option)]
+    recipe: Option<Utf8PathBuf>,
+
     /// route the conversion through a specific operation
     #[argh(option)]
     through: Vec<String>,
 
+    /// keep an operation or state out of the conversion
+    #[argh(option)]
+    avoid: Vec<String>,
+
     /// verbose ouput
     #[argh(switch, short = 'v')]
     verbose: Option<bool>,
+
+    /// in graph modes, highlight everything reachable from a state
+    #[argh(option)]
+    highlight: Option<String>,
+
+    /// in graph modes, cluster operations by setup
+    #[argh(switch)]
+    cluster: bool,
+
+    /// in completions mode, the shell to generate a script for (bash, zsh, fish)
+    #[argh(option)]
+    shell: Option<Shell>,
+}
+
+/// Check whether `arg` is a spelling of an option in `FakeArgs` that takes a value.
+fn takes_value(arg: &str) -> bool {
+    FakeArgs::get_args_info().flags.iter().any(|flag| {
+        matches!(flag.kind, FlagInfoKind::Option { .. })
+            && (arg == flag.long || flag.short.is_some_and(|c| arg == format!("-{}", c)))
+    })
+}
+
+/// The names in a table like `MODES`.
+fn names<T>(table: &[(T, &str)]) -> Vec<String> {
+    table.iter().map(|(_, name)| name.to_string()).collect()
+}
+
+/// Describe the options in `FakeArgs` for shell completion, with values from the driver.
+fn completion_opts(driver: &Driver) -> Vec<Opt> {
+    let states: Vec<String> = driver.states.values().map(|s| s.name.clone()).collect();
+    let ops: Vec<String> = driver.ops.values().map(|o| o.name.clone()).collect();
+
+    // Suggest the keys that the driver looks up along with anything in the config file.
+    let mut keys = run::config_keys(driver);
+    if let Ok(config_data) = config::load_config(&driver.name) {
+        keys.extend(config::config_keys(&config_data));
+    }
+    let keys: Vec<String> = keys.into_iter().collect();
+
+    FakeArgs::get_args_info()
+        .flags
+        .iter()
+        .map(|flag| {
+            let long = flag.long.trim_start_matches("--");
+            let values = match (&flag.kind, long) {
+                (FlagInfoKind::Switch, _) => Values::None,
+                (_, "output" | "report" | "recipe") => Values::File,
+                (_, "dir") => Values::Dir,
+                (_, "from" | "to" | "highlight") => Values::Words(states.clone()),
+                (_, "through") => Values::Words(ops.clone()),
+                (_, "avoid") => Values::Words(ops.iter().chain(&states).cloned().collect()),
+                (_, "mode") => Values::Words(names(MODES)),
+                (_, "shell") => Values::Words(names(SHELLS)),
+                (_, "set" | "sweep") => Values::Assign(keys.clone()),
+                _ => Values::Text,
+            };
+            Opt {
+                long,
+                short: flag.short,
+                help: flag.description,
+                values,
+                repeat: flag.optionality == Optionality::Repeating,
+            }
+        })
+        .collect()
 }
 
-fn from_state(driver: &Driver, args: &FakeArgs) -> anyhow::Result<StateRef> {
+/// Print a shell completion script.
+fn show_completions(driver: &Driver, args: &FakeArgs) -> anyhow::Result<()> {
+    let shell = args.shell.ok_or(anyhow!("specify a shell with --shell"))?;
+    let completions = Completions {
+        prog: &driver.name,
+        opts: completion_opts(driver),
+    };
+    completions.write(shell, &mut std::io::stdout())?;
+    Ok(())
+}
+
+/// An error for an unknown name, with a suggestion if there's a similar valid name.
+fn unknown(what: &str, name: &str, suggestion: Option<&str>) -> anyhow::Error {
+    match suggestion {
+        Some(s) => anyhow!("unknown {} {} (did you mean {}?)", what, name, s),
+        None => anyhow!("unknown {} {}", what, name),
+    }
+}
+
+/// Look up a state by name, or produce an error with a suggestion.
+fn get_state(driver: &Driver, what: &str, name: &str) -> anyhow::Result<StateRef> {
+    driver
+        .get_state(name)
+        .ok_or_else(|| unknown(what, name, driver.suggest_state(name)))
+}
+
+/// The input file, or `None` if the input comes from stdin, which is also spelled `-`.
+fn input_file(args: &FakeArgs) -> Option<&Utf8Path> {
+    args.input.as_deref().filter(|p| p.as_str() != "-")
+}
+
+/// Guess a file's state from its extension. If that doesn't settle it, look at the file's
+/// contents, choosing among the states with the file's extension if there are several.
+fn guess_file_state(driver: &Driver, path: &Utf8Path) -> anyhow::Result<StateRef> {
+    let err = match driver.guess_state(path) {
+        Ok(state) => return Ok(state),
+        Err(e) => e,
+    };
+
+    let read_head = || {
+        let mut head = vec![];
+        let file = std::fs::File::open(path).ok()?;
+        file.take(driver::SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .ok()?;
+        Some(head)
+    };
+    let candidates = driver.guess_states(path);
+    let sniffed = read_head().and_then(|head| {
+        if candidates.is_empty() {
+            driver.sniff_state(&head)
+        } else {
+            candidates
+                .into_iter()
+                .find(|s| driver.states[*s].sniff.is_some_and(|sniff| sniff(&head)))
+        }
+    });
+    sniffed.ok_or_else(|| anyhow::Error::from(err).context("specify the input state with --from"))
+}
+
+/// Find the input state, from `--from`, the input file, or the contents of stdin, if they have
+/// been read.
+fn from_state(
+    driver: &Driver,
+    args: &FakeArgs,
+    stdin_data: Option<&[u8]>,
+) -> anyhow::Result<StateRef> {
     match &args.from {
-        Some(name) => driver
-            .get_state(name)
-            .ok_or(anyhow!("unknown --from state")),
-        None => match args.input {
-            Some(ref input) => driver
-                .guess_state(input)
-                .ok_or(anyhow!("could not infer input state")),
-            None => bail!("specify an input file or use --from"),
+        Some(name) => get_state(driver, "--from state", name),
+        None => match (input_file(args), stdin_data) {
+            (Some(input), _) => guess_file_state(driver, input),
+            (None, Some(data)) => driver.sniff_state(data).ok_or(anyhow!(
+                "could not infer input state from stdin; use --from"
+            )),
+            (None, None) => bail!("specify an input file or use --from"),
         },
     }
 }
 
-fn to_state(driver: &Driver, args: &FakeArgs) -> anyhow::Result<StateRef> {
-    match &args.to {
-        Some(name) => driver.get_state(name).ok_or(anyhow!("unknown --to state")),
-        None => match &args.output {
-            Some(out) => driver
-                .guess_state(out)
-                .ok_or(anyhow!("could not infer output state")),
-            None => Err(anyhow!("specify an output file or use --to")),
-        },
+/// Find the targets, pairing up the `--to` states with the `-o` files in order. Without `--to`,
+/// the states come from the files' extensions.
+fn targets(driver: &Driver, args: &FakeArgs) -> anyhow::Result<Vec<Target>> {
+    let states = args
+        .to
+        .iter()
+        .map(|name| get_state(driver, "--to state", name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let files = &args.output;
+    match (states.len(), files.len()) {
+        (0, 0) => bail!("specify an output file or use --to"),
+        (0, _) => files
+            .iter()
+            .map(|file| {
+                let state = driver.guess_state(file).map_err(|e| {
+                    anyhow::Error::from(e).context("specify the output state with --to")
+                })?;
+                Ok(Target {
+                    state,
+                    file: Some(file.clone()),
+                })
+            })
+            .collect(),
+        (_, 0) => Ok(states
+            .into_iter()
+            .map(|state| Target { state, file: None })
+            .collect()),
+        (n, m) if n == m => Ok(states
+            .into_iter()
+            .zip(files)
+            .map(|(state, file)| Target {
+                state,
+                file: Some(file.clone()),
+            })
+            .collect()),
+        _ => bail!("give one output file (-o) for each --to state"),
+    }
+}
+
+/// The persistent build directory to use for this invocation, if any. Only runs on an input file
+/// use one. The input comes from the command line, or from the recipe when replaying one.
+fn persistent_dir(
+    driver: &Driver,
+    args: &FakeArgs,
+    input: Option<&Utf8Path>,
+) -> anyhow::Result<Option<Utf8PathBuf>> {
+    let input = match (&args.mode, &args.dir, input) {
+        (Mode::Run | Mode::Replay, None, Some(input)) if !args.fresh => input,
+        _ => return Ok(None),
+    };
+    let config = global_config(driver)?;
+    if !config.persistent_build_dir {
+        return Ok(None);
     }
+    let root = workdir::cache_root(&driver.name, &config)?;
+    Ok(Some(workdir::input_dir(&root, input)?))
+}
+
+fn global_config(driver: &Driver) -> anyhow::Result<GlobalConfig> {
+    let config = config::load_config(&driver.name)?
+        .extract()
+        .map_err(Error::from)?;
+    Ok(config)
 }
 
-fn get_request(driver: &Driver, args: &FakeArgs) -> anyhow::Result<Request> {
+/// Parse the `--sweep key=v1,v2,...` argument. The values can instead come from a file, one per
+/// line, with `key=@file`.
+fn get_sweep(args: &FakeArgs) -> anyhow::Result<Sweep> {
+    let arg = args
+        .sweep
+        .as_ref()
+        .ok_or(anyhow!("sweep mode requires --sweep"))?;
+    let (key, values) = arg.split_once('=').ok_or(anyhow!(
+        "--sweep must be in key=v1,v2,... or key=@file form"
+    ))?;
+    let values: Vec<String> = match values.strip_prefix('@') {
+        Some(path) => {
+            let path = Utf8Path::new(path);
+            std::fs::read_to_string(path)
+                .map_err(Error::at(path))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect()
+        }
+        // Skip empty values, like the one after a trailing comma, as we skip blank lines above.
+        None => values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+    if values.is_empty() {
+        bail!("no values to sweep over for {}", key);
+    }
+    Ok(Sweep {
+        key: key.to_string(),
+        values,
+    })
+}
+
+/// Parse the `--set key=value` arguments.
+fn config_overrides(args: &FakeArgs) -> anyhow::Result<Vec<(&str, &str)>> {
+    args.set
+        .iter()
+        .map(|set| {
+            set.split_once('=')
+                .ok_or(anyhow!("--set arguments must be in key=value form"))
+        })
+        .collect()
+}
+
+fn get_request(
+    driver: &Driver,
+    args: &FakeArgs,
+    persistent_dir: Option<&Utf8Path>,
+    stdin_data: Option<&[u8]>,
+) -> anyhow::Result<Request> {
     // The default working directory (if not specified) depends on the mode.
     let default_workdir = driver.default_workdir();
     let workdir = args.dir.as_deref().unwrap_or_else(|| match args.mode {
-        Mode::Generate | Mode::Run => default_workdir.as_ref(),
+        Mode::Run => persistent_dir.unwrap_or(&default_workdir),
+        Mode::Generate | Mode::Sweep | Mode::Recipe => default_workdir.as_ref(),
         _ => Utf8Path::new("."),
     });
 
+    // Operations are unavailable if they need configuration that isn't there, including the
+    // `--set` values. A swept key will be set, so it counts too.
+    let mut config_data = config::load_config(&driver.name)?;
+    for (key, value) in config_overrides(args)? {
+        config_data = config::set_value(config_data, key, value);
+    }
+    if let Some((key, _)) = args.sweep.as_ref().and_then(|s| s.split_once('=')) {
+        config_data = config::set_value(config_data, key, "");
+    }
+
     // Find all the operations to route through.
     let through: Result<Vec<_>, _> = args
         .through
@@ -126,60 +466,529 @@ fn get_request(driver: &Driver, args: &FakeArgs) -> anyhow::Result<Request> {
         .map(|s| {
             driver
                 .get_op(s)
-                .ok_or(anyhow!("unknown --through op {}", s))
+                .ok_or_else(|| unknown("--through op", s, driver.suggest_op(s)))
         })
         .collect();
 
+    // Find the operations and states to avoid, from both the config file and the command line. A
+    // name can refer to both an operation and a state.
+    let global: GlobalConfig = config_data.extract().map_err(Error::from)?;
+    let mut avoid_ops = vec![];
+    let mut avoid_states = vec![];
+    let avoid = global.avoid.iter().map(|s| ("avoid config entry", s));
+    for (what, name) in avoid.chain(args.avoid.iter().map(|s| ("--avoid op or state", s))) {
+        let op = driver.get_op(name);
+        let state = driver.get_state(name);
+        if op.is_none() && state.is_none() {
+            let suggestion = driver
+                .suggest_op(name)
+                .or_else(|| driver.suggest_state(name));
+            return Err(unknown(what, name, suggestion));
+        }
+        avoid_ops.extend(op);
+        avoid_states.extend(state);
+    }
+
     Ok(Request {
-        start_file: args.input.clone(),
-        start_state: from_state(driver, args)?,
-        end_file: args.output.clone(),
-        end_state: to_state(driver, args)?,
+        start_file: input_file(args).map(Utf8Path::to_path_buf),
+        start_state: from_state(driver, args, stdin_data)?,
+        targets: targets(driver, args)?,
         through: through?,
         workdir: workdir.into(),
+        excluded: driver.unavailable_ops(&config_data),
+        avoid_ops,
+        avoid_states,
     })
 }
 
-pub fn cli(driver: &Driver) -> anyhow::Result<()> {
-    let args: FakeArgs = argh::from_env();
+/// Draw the entire driver graph, which does not require a plan.
+fn show_graph(driver: &Driver, args: &FakeArgs) -> anyhow::Result<()> {
+    let highlight = match &args.highlight {
+        Some(name) => Some(get_state(driver, "--highlight state", name)?),
+        None => None,
+    };
+    let graph = Graph::new(
+        driver,
+        GraphOptions {
+            highlight,
+            cluster: args.cluster,
+        },
+    );
+    let mut out = std::io::stdout();
+    match args.mode {
+        Mode::ShowMermaid => graph.write_mermaid(&mut out)?,
+        _ => graph.write_dot(&mut out)?,
+    }
+    Ok(())
+}
 
-    // Make a plan.
-    let req = get_request(driver, &args)?;
-    let workdir = req.workdir.clone();
-    let plan = driver.plan(req).ok_or(anyhow!("could not find path"))?;
+/// Remove build directories: the `--dir` directory, if given; the input file's persistent
+/// directory, if there is an input; or otherwise all the persistent directories and the default
+/// one. Directories that the driver did not create are never removed.
+fn clean(driver: &Driver, args: &FakeArgs) -> anyhow::Result<()> {
+    let name = &driver.name;
+    let min_age = args.older_than;
+    let mut removed = vec![];
+    if let Some(dir) = &args.dir {
+        if workdir::clean_dir(dir, name, min_age)? {
+            removed.push(dir.clone());
+        }
+    } else {
+        let root = workdir::cache_root(name, &global_config(driver)?)?;
+        match input_file(args) {
+            Some(input) => {
+                let dir = workdir::input_dir(&root, input)?;
+                if workdir::clean_dir(&dir, name, min_age)? {
+                    removed.push(dir);
+                }
+            }
+            None => {
+                removed.extend(workdir::clean_all(&root, name, min_age)?);
+
+                // The default directory is left behind by `--keep` or a crash. It is not an
+                // error if it's not ours, since the user did not ask for it specifically.
+                let dir = driver.default_workdir();
+                match workdir::clean_dir(&dir, name, min_age) {
+                    Ok(true) => removed.push(dir),
+                    Ok(false) => {}
+                    Err(e @ Error::NotWorkdir(_)) => eprintln!("warning: {}", e),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+    for dir in removed {
+        eprintln!("removed {}", dir);
+    }
+    Ok(())
+}
+
+/// Parse an age like `30s`, `15m`, `12h`, or `7d`.
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let split = value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (num, unit) = value.split_at(split);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| "age must be a number followed by s, m, h, or d".to_string())?;
+    let scale: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err("age must be a number followed by s, m, h, or d".to_string()),
+    };
+    let secs = num
+        .checked_mul(scale)
+        .ok_or_else(|| "age too large".to_string())?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Run the command-line interface for a driver. Errors are printed to stderr, along with their
+/// causes, and produce a failing exit code.
+pub fn cli(driver: &Driver) -> ExitCode {
+    match cli_main(driver) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            print_error(&e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Print an error and its causes to stderr.
+pub fn print_error(e: &anyhow::Error) {
+    eprintln!("error: {}", e);
+    for cause in e.chain().skip(1) {
+        eprintln!("  caused by: {}", cause);
+    }
+}
+
+/// Execute the plan step by step with the native executor, which can report progress and timing.
+fn run_native(run: &Run, workdir: &Utf8Path, args: &FakeArgs) -> anyhow::Result<()> {
+    let res = if args.progress {
+        let mut reporter = TermReporter::new(run.global_config.verbose);
+        run.execute(workdir, None, &mut reporter)?
+    } else {
+        // Show the build's output on stderr as it happens, as Ninja would.
+        let mut echo = |event: &Event| {
+            if let Event::Output { line, .. } = event {
+                eprintln!("{}", line);
+            }
+        };
+        run.execute(workdir, None, &mut echo)?
+    };
+
+    let report = Report::new(run, &res);
+    if args.timing {
+        report.write_table(&mut std::io::stderr())?;
+    }
+    if let Some(path) = &args.report {
+        let mut file = std::fs::File::create(path).map_err(Error::at(path))?;
+        report.write_json(&mut file)?;
+    }
+
+    if let Some(step) = res.steps.iter().find(|s| !s.status.success()) {
+        bail!(
+            "operation {} failed ({})",
+            run.driver.ops[step.op].name,
+            step.status
+        );
+    }
+    if let Output::Bytes(bytes) = res.output {
+        std::io::stdout().write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Build the plan for each value in the sweep and print a table of the results.
+fn run_sweep(run: &Run, workdir: &Utf8Path, sweep: &Sweep, args: &FakeArgs) -> anyhow::Result<()> {
+    let res = run.run_sweep(sweep, workdir)?;
+    res.write_table(&mut std::io::stdout())?;
+    if let Some(path) = &args.report {
+        let mut file = std::fs::File::create(path).map_err(Error::at(path))?;
+        res.write_json(&mut file)?;
+    }
+
+    if !res.success() {
+        let failed = res.runs.iter().filter(|r| !r.success);
+        let values: Vec<_> = failed.map(|r| r.value.as_str()).collect();
+        bail!("the build failed for {} = {}", sweep.key, values.join(", "));
+    }
+    Ok(())
+}
+
+/// Drop a positional `-` from the arguments, which argh would reject as an unknown option. A `-`
+/// that follows an option that takes a value is that option's value, so it stays.
+fn drop_stdin_arg(args: &[String]) -> Vec<&str> {
+    let mut strs: Vec<&str> = vec![];
+    for arg in args {
+        if arg != "-" || strs.last().is_some_and(|prev| takes_value(prev)) {
+            strs.push(arg);
+        }
+    }
+    strs
+}
+
+/// Parse the command-line arguments, like `argh::from_env`, except that a positional `-` means
+/// stdin. We drop it, since reading stdin is also what happens when there's no input file.
+fn parse_args() -> FakeArgs {
+    let strings: Vec<String> = std::env::args().collect();
+    let arg0 = strings.first().map(|s| s.as_str()).unwrap_or_default();
+    let cmd = Utf8Path::new(arg0).file_name().unwrap_or(arg0);
+    let strs = drop_stdin_arg(strings.get(1..).unwrap_or_default());
+
+    FakeArgs::from_args(&[cmd], &strs).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    early_exit.output, cmd
+                );
+                1
+            }
+        })
+    })
+}
+
+/// Plan the build for the command-line arguments and set up a run.
+fn plan_run<'a>(
+    driver: &'a Driver,
+    args: &FakeArgs,
+    persistent_dir: Option<&Utf8Path>,
+) -> anyhow::Result<Run<'a>> {
+    // To infer the state of input from stdin, we need to read it up front.
+    let stdin = std::io::stdin();
+    let stdin_data = if args.from.is_none() && input_file(args).is_none() && !stdin.is_terminal() {
+        let mut data = vec![];
+        stdin.lock().read_to_end(&mut data)?;
+        Some(data)
+    } else {
+        None
+    };
+
+    let req = get_request(driver, args, persistent_dir, stdin_data.as_deref())?;
+    let plan = driver.plan(req)?;
 
     // Configure.
-    let mut run = Run::new(driver, plan);
+    let mut run = Run::new(driver, plan)?;
+
+    if let Some(data) = stdin_data {
+        run.set_stdin(data);
+    }
+    Ok(run)
+}
+
+/// Read the build recipe in `--recipe`.
+fn read_recipe(args: &FakeArgs) -> anyhow::Result<Recipe> {
+    let path = args
+        .recipe
+        .as_ref()
+        .ok_or(anyhow!("replay mode requires --recipe"))?;
+    Ok(Recipe::read(path)?)
+}
+
+/// Set up a run for a build recipe, with our own configuration and build directory, and warn
+/// about any changes to the driver since it was recorded.
+fn replay_run<'a>(
+    driver: &'a Driver,
+    recipe: Recipe,
+    args: &FakeArgs,
+    persistent_dir: Option<&Utf8Path>,
+) -> anyhow::Result<Run<'a>> {
+    let default_workdir = driver.default_workdir();
+    let workdir = args
+        .dir
+        .as_deref()
+        .or(persistent_dir)
+        .unwrap_or(&default_workdir);
+    let config_data = config::load_config(&driver.name)?;
+    let (run, warnings) = recipe.into_run(driver, config_data, workdir)?;
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(run)
+}
+
+/// Write a build recipe for the run to a file, or to stdout. Input from stdin is gone once we've
+/// read it, so a recipe can't use it.
+fn write_recipe(run: &Run, path: Option<&Utf8Path>) -> anyhow::Result<()> {
+    if run.plan.stdin {
+        bail!("a build recipe cannot read its input from stdin; use an input file");
+    }
+    let recipe = Recipe::new(run)?;
+    match path {
+        Some(path) => {
+            let mut file = std::fs::File::create(path).map_err(Error::at(path))?;
+            recipe.write_json(&mut file)?;
+        }
+        None => recipe.write_json(&mut std::io::stdout())?,
+    }
+    Ok(())
+}
+
+fn cli_main(driver: &Driver) -> anyhow::Result<()> {
+    let args = parse_args();
+
+    // Graph modes show the whole driver, so they don't need a plan.
+    match args.mode {
+        Mode::ShowGraph | Mode::ShowMermaid => return show_graph(driver, &args),
+        Mode::Completions => return show_completions(driver, &args),
+        Mode::Clean => return clean(driver, &args),
+        _ => {}
+    }
+
+    let sweep = match args.mode {
+        Mode::Sweep => Some(get_sweep(&args)?),
+        _ => None,
+    };
+
+    // Make a plan, or take one from a recipe.
+    let recipe = match args.mode {
+        Mode::Replay => Some(read_recipe(&args)?),
+        _ => None,
+    };
+    let input = match &recipe {
+        Some(recipe) => recipe.input(),
+        None => input_file(&args),
+    };
+    let persistent_dir = persistent_dir(driver, &args, input)?;
+    let mut run = match recipe {
+        Some(recipe) => replay_run(driver, recipe, &args, persistent_dir.as_deref())?,
+        None => plan_run(driver, &args, persistent_dir.as_deref())?,
+    };
+    let workdir = run.plan.workdir.clone();
+
+    // Override some global config options. Persistent build directories are, of course, kept.
+    // These stick when `--watch` reloads the config file.
+    let persistent = persistent_dir.is_some();
+    let (keep, verbose, stream) = (args.keep, args.verbose, args.stream);
+    let (jobs, keep_going, dry_run, explain) =
+        (args.jobs, args.keep_going, args.dry_run, args.explain);
+    let ninja_args = args.ninja_arg.clone();
+    run.override_global(move |global| {
+        if persistent {
+            global.keep_build_dir = true;
+        }
+        if let Some(keep) = keep {
+            global.keep_build_dir = keep;
+        }
+        if let Some(verbose) = verbose {
+            global.verbose = verbose;
+        }
+        if let Some(stream) = stream {
+            global.stream_stdout = stream;
+        }
+        if let Some(jobs) = jobs {
+            global.jobs = Some(jobs);
+        }
+        if let Some(keep_going) = keep_going {
+            global.keep_going = keep_going;
+        }
+        if let Some(dry_run) = dry_run {
+            global.dry_run = dry_run;
+        }
+        if let Some(explain) = explain {
+            global.explain = explain;
+        }
+        global.ninja_args.extend(ninja_args.iter().cloned());
+    });
 
-    // Override some global config options.
-    if let Some(keep) = args.keep {
-        run.global_config.keep_build_dir = keep;
+    // Use `--set` arguments to override configuration values. Warn about keys that nothing seems
+    // to use, including a swept key, since they are likely typos.
+    let mut known_keys = None;
+    let mut check_key = |key: &str, config_data: &figment::Figment| {
+        let known_keys = known_keys.get_or_insert_with(|| {
+            let mut keys = run::config_keys(driver);
+            keys.extend(config::config_keys(config_data));
+            keys
+        });
+        if !known_keys.contains(key) {
+            match driver::suggest(key, known_keys.iter().map(|k| k.as_str())) {
+                Some(s) => eprintln!("warning: unknown config key {} (did you mean {}?)", key, s),
+                None => eprintln!("warning: unknown config key {}", key),
+            }
+        }
+    };
+    for (key, value) in config_overrides(&args)? {
+        check_key(key, &run.config_data);
+        run.set_config(key, value);
     }
-    if let Some(verbose) = args.verbose {
-        run.global_config.verbose = verbose;
+    if let Some(sweep) = &sweep {
+        check_key(&sweep.key, &run.config_data);
     }
 
-    // Use `--set` arguments to override configuration values.
-    for set in args.set {
-        let mut parts = set.splitn(2, '=');
-        let key = parts.next().unwrap();
-        let value = parts
-            .next()
-            .ok_or(anyhow!("--set arguments must be in key=value form"))?;
-        let dict = figment::util::nest(key, value.into());
-        run.config_data = run
-            .config_data
-            .merge(figment::providers::Serialized::defaults(dict));
+    // Record the plan that's about to run, so it can be replayed later.
+    if let (Mode::Run, Some(path)) = (&args.mode, &args.recipe) {
+        write_recipe(&run, Some(path))?;
     }
 
     // Execute.
     match args.mode {
         Mode::ShowPlan => run.show(),
+        Mode::ShowPlanJson => run.show_json(),
         Mode::ShowDot => run.show_dot(),
         Mode::EmitNinja => run.emit_to_stdout()?,
         Mode::Generate => run.emit_to_dir(&workdir)?,
-        Mode::Run => run.emit_and_run(&workdir)?,
+        Mode::Run if args.watch => {
+            if run.plan.stdin {
+                bail!("--watch requires an input file");
+            }
+            run.emit_and_watch(&workdir)?
+        }
+        Mode::Run | Mode::Replay if args.progress || args.timing || args.report.is_some() => {
+            run_native(&run, &workdir, &args)?
+        }
+        Mode::Run | Mode::Replay => run.emit_and_run(&workdir)?,
+        Mode::Recipe => write_recipe(&run, args.recipe.as_deref())?,
+        Mode::Sweep => run_sweep(&run, &workdir, sweep.as_ref().unwrap(), &args)?,
+        Mode::ShowGraph | Mode::ShowMermaid | Mode::Completions | Mode::Clean => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::DriverBuilder;
+    use crate::testing::TempDir;
+
+    fn args(strs: &[&str]) -> FakeArgs {
+        let strings: Vec<String> = strs.iter().map(|s| s.to_string()).collect();
+        FakeArgs::from_args(&["fake"], &drop_stdin_arg(&strings)).unwrap()
+    }
+
+    #[test]
+    fn dash_is_stdin_or_value() {
+        let parsed = args(&["-", "--to", "b", "--recipe", "-", "-o", "-"]);
+        assert!(parsed.input.is_none());
+        assert_eq!(parsed.recipe.as_deref(), Some("-".into()));
+        assert_eq!(parsed.output, ["-"]);
+
+        // A switch doesn't take the `-` as its value.
+        let parsed = args(&["--keep", "-", "--to", "b"]);
+        assert!(parsed.input.is_none());
+        assert_eq!(parsed.keep, Some(true));
+    }
+
+    #[test]
+    fn mode_names_round_trip() {
+        for &(mode, name) in MODES {
+            assert!(Mode::from_str(name).unwrap() == mode);
+            assert_eq!(mode.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn mode_help_lists_every_mode() {
+        let info = FakeArgs::get_args_info();
+        let mode = info.flags.iter().find(|f| f.long == "--mode").unwrap();
+        for (_, name) in MODES {
+            assert!(
+                mode.description.contains(name),
+                "--mode help omits {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn contents_settle_ambiguous_extensions() {
+        let mut bld = DriverBuilder::new("clitest");
+        let v = bld.state("verilog", &["sv"]);
+        let sv = bld.state("systemverilog", &["sv"]);
+        let a = bld.state("a", &["a"]);
+        bld.rule(&[], v, a, "v_to_a");
+        bld.rule(&[], sv, a, "sv_to_a");
+        bld.rule(&[], a, v, "a_to_v");
+        bld.sniff(sv, |head| head.starts_with(b"interface"));
+        let driver = bld.build().unwrap();
+
+        let tmp = TempDir::new("cli-sniff");
+        let file = tmp.0.join("x.sv");
+        std::fs::write(&file, "interface bus;").unwrap();
+        assert_eq!(guess_file_state(&driver, &file).unwrap(), sv);
+
+        // Without a matching sniffer, the extension's ambiguity is the error.
+        std::fs::write(&file, "module top;").unwrap();
+        let err = guess_file_state(&driver, &file).unwrap_err();
+        assert!(format!("{:#}", err).contains("could be verilog or systemverilog"));
+    }
+
+    #[test]
+    fn ages_have_units_and_limits() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_age("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("7w").is_err());
+        assert_eq!(
+            parse_age(&format!("{}d", u64::MAX / 2)),
+            Err("age too large".into())
+        );
+    }
+
+    #[test]
+    fn sweep_values_skip_blanks() {
+        let sweep = get_sweep(&args(&["--sweep", "opt.level=1, 2,,3,"])).unwrap();
+        assert_eq!(sweep.key, "opt.level");
+        assert_eq!(sweep.values, ["1", "2", "3"]);
+
+        let tmp = TempDir::new("cli-sweep");
+        let file = tmp.0.join("levels.txt");
+        std::fs::write(&file, "1\n\n  2  \n").unwrap();
+        let sweep = get_sweep(&args(&["--sweep", &format!("opt.level=@{}", file)])).unwrap();
+        assert_eq!(sweep.values, ["1", "2"]);
+
+        let Err(err) = get_sweep(&args(&["--sweep", "opt.level=,"])) else {
+            panic!("expected no values");
+        };
+        assert_eq!(err.to_string(), "no values to sweep over for opt.level");
+        assert!(get_sweep(&args(&["--sweep", "opt.level"])).is_err());
+    }
+}
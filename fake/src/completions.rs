@@ -0,0 +1,274 @@
+use std::io::Write;
+use std::str::FromStr;
+
+/// A shell that we can generate a completion script for.
+#[derive(Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Each shell's name on the command line.
+pub const SHELLS: &[(Shell, &str)] = &[
+    (Shell::Bash, "bash"),
+    (Shell::Zsh, "zsh"),
+    (Shell::Fish, "fish"),
+];
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        SHELLS
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(shell, _)| *shell)
+            .ok_or("unknown shell".to_string())
+    }
+}
+
+/// The values that a command-line option can take.
+pub enum Values {
+    /// A switch that takes no value.
+    None,
+
+    /// An arbitrary value that we can't suggest anything for.
+    Text,
+
+    /// A filename.
+    File,
+
+    /// A directory name.
+    Dir,
+
+    /// One of a fixed list of words.
+    Words(Vec<String>),
+
+    /// A `key=value` assignment, where we can suggest the keys.
+    Assign(Vec<String>),
+}
+
+/// A command-line option to complete.
+pub struct Opt {
+    pub long: &'static str,
+    pub short: Option<char>,
+    pub help: &'static str,
+    pub values: Values,
+
+    /// Can the option be given more than once?
+    pub repeat: bool,
+}
+
+impl Opt {
+    /// All the spellings of the option, like `-o` and `--output`.
+    fn flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(short) = self.short {
+            flags.push(format!("-{}", short));
+        }
+        flags.push(format!("--{}", self.long));
+        flags
+    }
+}
+
+/// Quote a string for a shell as single-quoted text, ending and restarting the quotes around any
+/// `'` in it.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// A completion script for a program that takes a single positional input file and some options.
+pub struct Completions<'a> {
+    pub prog: &'a str,
+    pub opts: Vec<Opt>,
+}
+
+impl<'a> Completions<'a> {
+    pub fn write(&self, shell: Shell, out: &mut dyn Write) -> std::io::Result<()> {
+        match shell {
+            Shell::Bash => self.write_bash(out),
+            Shell::Zsh => self.write_zsh(out),
+            Shell::Fish => self.write_fish(out),
+        }
+    }
+
+    /// A name for shell functions that is safe to use as an identifier.
+    fn func_name(&self) -> String {
+        format!(
+            "_{}",
+            self.prog.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        )
+    }
+
+    fn write_bash(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let func = self.func_name();
+        writeln!(out, "{}() {{", func)?;
+        writeln!(out, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+        writeln!(out, "    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"")?;
+
+        // Complete values for the previous option.
+        writeln!(out, "    case \"$prev\" in")?;
+        for opt in &self.opts {
+            let action = match &opt.values {
+                Values::None => continue,
+                Values::Text => "COMPREPLY=()".to_string(),
+                Values::File => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+                Values::Dir => "COMPREPLY=($(compgen -d -- \"$cur\"))".to_string(),
+                Values::Words(words) => {
+                    format!(
+                        "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                        words.join(" ")
+                    )
+                }
+                Values::Assign(keys) => {
+                    let keys: Vec<_> = keys.iter().map(|k| format!("{}=", k)).collect();
+                    format!(
+                        "compopt -o nospace; COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                        keys.join(" ")
+                    )
+                }
+            };
+            writeln!(out, "        {})", opt.flags().join("|"))?;
+            writeln!(out, "            {}", action)?;
+            writeln!(out, "            return;;")?;
+        }
+        writeln!(out, "    esac")?;
+
+        // Otherwise, complete option names or the input file.
+        let flags: Vec<_> = self.opts.iter().flat_map(|o| o.flags()).collect();
+        writeln!(out, "    if [[ \"$cur\" == -* ]]; then")?;
+        writeln!(
+            out,
+            "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            flags.join(" ")
+        )?;
+        writeln!(out, "    else")?;
+        writeln!(out, "        COMPREPLY=($(compgen -f -- \"$cur\"))")?;
+        writeln!(out, "    fi")?;
+        writeln!(out, "}}")?;
+        writeln!(out, "complete -o filenames -F {} {}", func, self.prog)?;
+        Ok(())
+    }
+
+    fn write_zsh(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "#compdef {}", self.prog)?;
+        writeln!(out)?;
+        writeln!(out, "{}() {{", self.func_name())?;
+        writeln!(out, "    _arguments -s \\")?;
+        for opt in &self.opts {
+            let flags = opt.flags();
+            let names = if flags.len() > 1 {
+                format!("{{{}}}", flags.join(","))
+            } else {
+                flags[0].clone()
+            };
+            // The `*` must be quoted, since completion functions run with `nullglob` set.
+            let exclusive = if opt.repeat {
+                "'*'".to_string()
+            } else if flags.len() > 1 {
+                format!("'({})'", flags.join(" "))
+            } else {
+                String::new()
+            };
+            let action = match &opt.values {
+                Values::None => String::new(),
+                Values::Text => format!(":{}: ", opt.long),
+                Values::File => format!(":{}:_files", opt.long),
+                Values::Dir => format!(":{}:_files -/", opt.long),
+                Values::Words(words) => format!(":{}:({})", opt.long, words.join(" ")),
+                Values::Assign(keys) => {
+                    let keys: Vec<_> = keys.iter().map(|k| format!("{}=", k)).collect();
+                    // This ends up inside single quotes, so the empty suffix is `""`.
+                    format!(":{}:{{compadd -S \"\" -- {}}}", opt.long, keys.join(" "))
+                }
+            };
+            // `_arguments` takes a `]` in the description only if it's escaped.
+            let help = opt.help.replace('[', "\\[").replace(']', "\\]");
+            let spec = format!("[{}]{}", help, action);
+            writeln!(out, "        {}{}{} \\", exclusive, names, quote(&spec))?;
+        }
+        writeln!(out, "        '1:input:_files'")?;
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "{} \"$@\"", self.func_name())?;
+        Ok(())
+    }
+
+    fn write_fish(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        for opt in &self.opts {
+            write!(out, "complete -c {}", self.prog)?;
+            if let Some(short) = opt.short {
+                write!(out, " -s {}", short)?;
+            }
+            write!(out, " -l {}", opt.long)?;
+            match &opt.values {
+                Values::None => {}
+                Values::Text => write!(out, " -x")?,
+                Values::File => write!(out, " -r -F")?,
+                Values::Dir => write!(out, " -x -a '(__fish_complete_directories)'")?,
+                Values::Words(words) => write!(out, " -x -a '{}'", words.join(" "))?,
+                Values::Assign(keys) => {
+                    let keys: Vec<_> = keys.iter().map(|k| format!("{}=", k)).collect();
+                    write!(out, " -x -a '{}'", keys.join(" "))?
+                }
+            }
+            writeln!(out, " -d {}", quote(opt.help))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completions() -> Completions<'static> {
+        let opt = |long, short, help, values, repeat| Opt {
+            long,
+            short,
+            help,
+            values,
+            repeat,
+        };
+        Completions {
+            prog: "prog",
+            opts: vec![
+                opt("output", Some('o'), "an output file", Values::File, true),
+                opt("to", None, "a state", Values::Words(vec!["x".into()]), true),
+                opt("mode", Some('m'), "a mode", Values::Text, false),
+                opt(
+                    "keep",
+                    None,
+                    "keep the [build]'s files",
+                    Values::None,
+                    false,
+                ),
+                opt("set", None, "set", Values::Assign(vec!["k".into()]), true),
+            ],
+        }
+    }
+
+    #[test]
+    fn zsh_quotes_repeats() {
+        let mut out = vec![];
+        completions().write(Shell::Zsh, &mut out).unwrap();
+        let script = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = script.lines().map(str::trim).collect();
+        assert!(lines.contains(&"'*'{-o,--output}'[an output file]:output:_files' \\"));
+        assert!(lines.contains(&"'*'--to'[a state]:to:(x)' \\"));
+        assert!(lines.contains(&"'(-m --mode)'{-m,--mode}'[a mode]:mode: ' \\"));
+        assert!(lines.contains(&r"--keep'[keep the \[build\]'\''s files]' \"));
+        assert!(lines.contains(&"'*'--set'[set]:set:{compadd -S \"\" -- k=}' \\"));
+    }
+
+    #[test]
+    fn fish_quotes_help() {
+        let mut out = vec![];
+        completions().write(Shell::Fish, &mut out).unwrap();
+        let script = String::from_utf8(out).unwrap();
+        assert!(script
+            .lines()
+            .any(|line| line == r"complete -c prog -l keep -d 'keep the [build]'\''s files'"));
+    }
+}
@@ -1,9 +1,14 @@
+use crate::error::{Error, Result};
 use figment::{
     providers::{Format, Serialized, Toml},
+    value::{Dict, Value},
     Figment,
 };
 use serde::{Deserialize, Serialize};
-use std::{env, path::Path};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GlobalConfig {
@@ -15,6 +20,63 @@ pub struct GlobalConfig {
 
     /// Enable verbose output.
     pub verbose: bool,
+
+    /// In `run` mode, build each input file in its own directory under the cache root and keep it
+    /// between runs, so later runs only rebuild what changed.
+    pub persistent_build_dir: bool,
+
+    /// The directory for persistent build directories. The default is `~/.cache/driver_name`.
+    pub cache_dir: Option<String>,
+
+    /// In `run` mode, copy the final output to stdout while it is being written, instead of after
+    /// the build finishes.
+    pub stream_stdout: bool,
+
+    /// The number of jobs for Ninja to run in parallel, or Ninja's default if unset.
+    pub jobs: Option<usize>,
+
+    /// Keep building as much as possible after a command fails.
+    pub keep_going: bool,
+
+    /// Ask Ninja to show what it would run without running anything.
+    pub dry_run: bool,
+
+    /// Ask Ninja to explain why it runs each command.
+    pub explain: bool,
+
+    /// Extra arguments to pass to Ninja.
+    pub ninja_args: Vec<String>,
+
+    /// Operations and states that plans should never use, like the `--avoid` option.
+    pub avoid: Vec<String>,
+
+    /// Directories containing plugin executables to load. Relative paths are relative to the
+    /// directory containing the config file.
+    pub plugin_dirs: Vec<String>,
+}
+
+impl GlobalConfig {
+    /// The command-line arguments for Ninja that these options call for.
+    pub fn ninja_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(jobs) = self.jobs {
+            args.extend(["-j".to_string(), jobs.to_string()]);
+        }
+        if self.keep_going {
+            args.extend(["-k", "0"].map(String::from));
+        }
+        if self.dry_run {
+            args.push("-n".to_string());
+        }
+        if self.explain {
+            args.extend(["-d", "explain"].map(String::from));
+        }
+        if self.verbose {
+            args.push("-v".to_string());
+        }
+        args.extend(self.ninja_args.iter().cloned());
+        args
+    }
 }
 
 impl Default for GlobalConfig {
@@ -23,19 +85,82 @@ impl Default for GlobalConfig {
             ninja: "ninja".to_string(),
             keep_build_dir: false,
             verbose: false,
+            persistent_build_dir: true,
+            cache_dir: None,
+            stream_stdout: false,
+            jobs: None,
+            keep_going: false,
+            dry_run: false,
+            explain: false,
+            ninja_args: vec![],
+            avoid: vec![],
+            plugin_dirs: vec![],
         }
     }
 }
 
-/// Load configuration data from the standard config file location.
-pub(crate) fn load_config(name: &str) -> Figment {
+/// The location of the config file for the driver called `name`.
+pub(crate) fn config_path(name: &str) -> Result<PathBuf> {
     // The configuration is usually at `~/.config/driver_name.toml`.
-    let config_base = env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| {
-        let home = env::var("HOME").expect("$HOME not set");
-        home + "/.config"
-    });
-    let config_path = Path::new(&config_base).join(name).with_extension("toml");
+    let config_base = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => dir,
+        Err(_) => env::var("HOME").map_err(|_| Error::NoConfigDir)? + "/.config",
+    };
+    Ok(Path::new(&config_base).join(name).with_extension("toml"))
+}
 
+/// Load configuration data from the standard config file location.
+pub(crate) fn load_config(name: &str) -> Result<Figment> {
     // Use our defaults, overridden by the TOML config file.
-    Figment::from(Serialized::defaults(GlobalConfig::default())).merge(Toml::file(config_path))
+    Ok(Figment::from(Serialized::defaults(GlobalConfig::default()))
+        .merge(Toml::file(config_path(name)?)))
+}
+
+/// Set a configuration value, taking precedence over the existing ones. Nested keys are separated
+/// by dots.
+pub(crate) fn set_value(config: Figment, key: &str, value: &str) -> Figment {
+    let dict = figment::util::nest(key, value.into());
+    config.merge(Serialized::defaults(dict))
+}
+
+/// List the keys that have values in a configuration, with nested keys separated by dots.
+pub(crate) fn config_keys(config: &Figment) -> Vec<String> {
+    fn walk(prefix: &str, dict: &Dict, keys: &mut Vec<String>) {
+        for (key, value) in dict {
+            let key = format!("{}{}", prefix, key);
+            match value {
+                Value::Dict(_, inner) => walk(&format!("{}.", key), inner, keys),
+                _ => keys.push(key),
+            }
+        }
+    }
+    let mut keys = vec![];
+    if let Ok(dict) = config.extract::<Dict>() {
+        walk("", &dict, &mut keys);
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ninja_args_follow_the_config() {
+        assert!(GlobalConfig::default().ninja_args().is_empty());
+
+        let global = GlobalConfig {
+            jobs: Some(4),
+            keep_going: true,
+            dry_run: true,
+            explain: true,
+            verbose: true,
+            ninja_args: vec!["-w".into(), "dupbuild=err".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            global.ninja_args().join(" "),
+            "-j 4 -k 0 -n -d explain -v -w dupbuild=err"
+        );
+    }
 }
@@ -1,7 +1,10 @@
+use crate::error::{Error, Result};
 use crate::run;
 use camino::{Utf8Path, Utf8PathBuf};
 use cranelift_entity::{entity_impl, PrimaryMap, SecondaryMap};
 use pathdiff::diff_utf8_paths;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 /// A State is a type of file that Operations produce or consume.
 pub struct State {
@@ -15,10 +18,20 @@ pub struct State {
     /// Pseudo-states can only be final outputs; they are appropraite for representing actions that
     /// interact directly with the user, for example.
     pub extensions: Vec<String>,
+
+    /// Recognize a file in this state from the first few kilobytes of its contents, for inputs
+    /// whose extension doesn't tell us the state, such as stdin.
+    pub sniff: Option<SniffFn>,
 }
 
+/// A function that checks whether a file's contents look like a given state.
+pub type SniffFn = fn(&[u8]) -> bool;
+
+/// The number of bytes at the start of a file that sniffing functions get to see.
+pub const SNIFF_LEN: usize = 4096;
+
 /// A reference to a State.
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct StateRef(u32);
 entity_impl!(StateRef, "state");
 
@@ -29,10 +42,14 @@ pub struct Operation {
     pub output: StateRef,
     pub setups: Vec<SetupRef>,
     pub emit: Box<dyn run::EmitBuild>,
+
+    /// Configuration keys that must be set for the operation to be available. The planner does
+    /// not route through unavailable operations.
+    pub requires: Vec<String>,
 }
 
 /// A reference to an Operation.
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct OpRef(u32);
 entity_impl!(OpRef, "op");
 
@@ -48,13 +65,22 @@ pub struct SetupRef(u32);
 entity_impl!(SetupRef, "setup");
 
 impl State {
-    /// Check whether a filename extension indicates this state.
-    fn ext_matches(&self, ext: &str) -> bool {
-        self.extensions.iter().any(|e| e == ext)
+    /// Find the longest of this state's extensions that a filename ends with, if any. Extensions
+    /// may be compound, like `cider.json`.
+    fn ext_match(&self, filename: &str) -> Option<&str> {
+        self.extensions
+            .iter()
+            .filter(|ext| {
+                filename.len() > ext.len() + 1
+                    && filename.ends_with(ext.as_str())
+                    && filename[..filename.len() - ext.len()].ends_with('.')
+            })
+            .max_by_key(|ext| ext.len())
+            .map(|ext| ext.as_str())
     }
 
     /// Is this a "pseudo-state": doesn't correspond to an actual file, and must be an output state?
-    fn is_pseudo(&self) -> bool {
+    pub fn is_pseudo(&self) -> bool {
         self.extensions.is_empty()
     }
 }
@@ -62,15 +88,47 @@ impl State {
 /// Get a version of `path` that works when the working directory is `base`. This is
 /// opportunistically a relative path, but we can always fall back to an absolute path to make sure
 /// the path still works.
-pub fn relative_path(path: &Utf8Path, base: &Utf8Path) -> Utf8PathBuf {
+pub fn relative_path(path: &Utf8Path, base: &Utf8Path) -> Result<Utf8PathBuf> {
     match diff_utf8_paths(path, base) {
-        Some(p) => p,
-        None => path
-            .canonicalize_utf8()
-            .expect("could not get absolute path"),
+        Some(p) => Ok(p),
+        None if base.is_absolute() => {
+            // A relative path can't be expressed relative to an absolute base, so make it absolute
+            // instead. This works even for files that don't exist yet.
+            let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)
+                .map_err(|e| std::io::Error::other(e.into_io_error()))?;
+            Ok(cwd.join(path))
+        }
+        None => path.canonicalize_utf8().map_err(Error::at(path)),
     }
 }
 
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = diag + usize::from(ca != *cb);
+            diag = row[j + 1];
+            row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Find the candidate most similar to `name`, if any is close enough to be a plausible typo.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_dist = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(dist, _)| *dist <= max_dist)
+        .min_by_key(|(dist, _)| *dist)
+        .map(|(_, c)| c)
+}
+
 #[derive(PartialEq)]
 enum Destination {
     State(StateRef),
@@ -81,6 +139,10 @@ enum Destination {
 /// contains all the machinery to perform builds in a given ecosystem.
 pub struct Driver {
     pub name: String,
+
+    /// The driver's version, if it has one, which build recipes record.
+    pub version: Option<String>,
+
     pub setups: PrimaryMap<SetupRef, Setup>,
     pub states: PrimaryMap<StateRef, State>,
     pub ops: PrimaryMap<OpRef, Operation>,
@@ -89,7 +151,12 @@ pub struct Driver {
 impl Driver {
     /// Find a chain of Operations from the `start` state to the `end`, which may be a state or the
     /// final operation in the chain.
-    fn find_path_segment(&self, start: StateRef, end: Destination) -> Option<Vec<OpRef>> {
+    fn find_path_segment(
+        &self,
+        start: StateRef,
+        end: Destination,
+        excluded: &HashMap<OpRef, String>,
+    ) -> Option<Vec<OpRef>> {
         // Our start state is the input.
         let mut visited = SecondaryMap::<StateRef, bool>::new();
         visited[start] = true;
@@ -109,7 +176,7 @@ impl Driver {
 
             // Traverse any edge from the current state to an unvisited state.
             for (op_ref, op) in self.ops.iter() {
-                if op.input == cur_state && !visited[op.output] {
+                if op.input == cur_state && !visited[op.output] && !excluded.contains_key(&op_ref) {
                     state_queue.push(op.output);
                     visited[op.output] = true;
                     breadcrumbs[op.output] = Some(op_ref);
@@ -146,86 +213,397 @@ impl Driver {
     }
 
     /// Find a chain of operations from the `start` state to the `end` state, passing through each
-    /// `through` operation in order.
+    /// `through` operation in order and avoiding the `excluded` ones.
     pub fn find_path(
         &self,
         start: StateRef,
         end: StateRef,
         through: &[OpRef],
+        excluded: &HashMap<OpRef, String>,
     ) -> Option<Vec<OpRef>> {
         let mut cur_state = start;
         let mut op_path: Vec<OpRef> = vec![];
 
         // Build path segments through each through required operation.
         for op in through {
-            let segment = self.find_path_segment(cur_state, Destination::Op(*op))?;
+            if excluded.contains_key(op) {
+                return None;
+            }
+            let segment = self.find_path_segment(cur_state, Destination::Op(*op), excluded)?;
             op_path.extend(segment);
             cur_state = self.ops[*op].output;
         }
 
         // Build the final path segment to the destination state.
-        let segment = self.find_path_segment(cur_state, Destination::State(end))?;
+        let segment = self.find_path_segment(cur_state, Destination::State(end), excluded)?;
         op_path.extend(segment);
 
         Some(op_path)
     }
 
+    /// Find all the states that can be produced from `start` via some chain of operations,
+    /// including `start` itself.
+    pub fn reachable_states(&self, start: StateRef) -> Vec<StateRef> {
+        let mut visited = SecondaryMap::<StateRef, bool>::new();
+        visited[start] = true;
+        let mut reachable = vec![start];
+        let mut i = 0;
+        while i < reachable.len() {
+            let cur_state = reachable[i];
+            for op in self.ops.values() {
+                if op.input == cur_state && !visited[op.output] {
+                    visited[op.output] = true;
+                    reachable.push(op.output);
+                }
+            }
+            i += 1;
+        }
+        reachable
+    }
+
+    /// Find all the states that can produce `end` via some chain of operations, including `end`
+    /// itself.
+    pub fn reaching_states(&self, end: StateRef) -> Vec<StateRef> {
+        let mut visited = SecondaryMap::<StateRef, bool>::new();
+        visited[end] = true;
+        let mut reaching = vec![end];
+        let mut i = 0;
+        while i < reaching.len() {
+            let cur_state = reaching[i];
+            for op in self.ops.values() {
+                if op.output == cur_state && !visited[op.input] {
+                    visited[op.input] = true;
+                    reaching.push(op.input);
+                }
+            }
+            i += 1;
+        }
+        reaching
+    }
+
+    /// Describe why there is no path from `start` to `end`. If there would be one without the
+    /// `excluded` operations, say which of them it needs and why they were excluded. Otherwise,
+    /// list the states that are reachable from the start and the states that can reach the end.
+    pub fn explain_no_path(
+        &self,
+        start: StateRef,
+        end: StateRef,
+        through: &[OpRef],
+        excluded: &HashMap<OpRef, String>,
+    ) -> String {
+        let names = |states: Vec<StateRef>| {
+            states
+                .into_iter()
+                .map(|s| self.states[s].name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut msg = format!(
+            "could not find a path from {} to {}",
+            self.states[start].name, self.states[end].name
+        );
+        if !through.is_empty() {
+            let ops: Vec<_> = through
+                .iter()
+                .map(|op| self.ops[*op].name.as_str())
+                .collect();
+            msg.push_str(&format!(" through {}", ops.join(", ")));
+        }
+        let blocked = self.excluded_on_path(start, end, through, excluded);
+        if !blocked.is_empty() {
+            for (op, reason) in blocked {
+                msg.push_str(&format!("\n{} is excluded: {}", self.ops[op].name, reason));
+            }
+            return msg;
+        }
+        msg.push_str(&format!(
+            "\nstates reachable from {}: {}",
+            self.states[start].name,
+            names(self.reachable_states(start))
+        ));
+        msg.push_str(&format!(
+            "\nstates that can reach {}: {}",
+            self.states[end].name,
+            names(self.reaching_states(end))
+        ));
+        msg
+    }
+
+    /// Find the excluded operations that the shortest path from `start` to `end` would use if they
+    /// were allowed, with the reason each was excluded.
+    pub fn excluded_on_path(
+        &self,
+        start: StateRef,
+        end: StateRef,
+        through: &[OpRef],
+        excluded: &HashMap<OpRef, String>,
+    ) -> Vec<(OpRef, String)> {
+        let path = self
+            .find_path(start, end, through, &HashMap::new())
+            .unwrap_or_default();
+        path.into_iter()
+            .filter_map(|op| Some((op, excluded.get(&op)?.clone())))
+            .collect()
+    }
+
+    /// Find the operations that are unavailable because `config` lacks keys they require, with
+    /// an explanation for each.
+    pub fn unavailable_ops(&self, config: &figment::Figment) -> HashMap<OpRef, String> {
+        self.ops
+            .iter()
+            .filter_map(|(op_ref, op)| {
+                let missing: Vec<_> = op
+                    .requires
+                    .iter()
+                    .filter(|key| config.find_value(key).is_err())
+                    .map(String::as_str)
+                    .collect();
+                match missing[..] {
+                    [] => None,
+                    [key] => Some((op_ref, format!("missing config key {}", key))),
+                    _ => Some((
+                        op_ref,
+                        format!("missing config keys {}", missing.join(", ")),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Suggest a state name that is similar to a misspelled one.
+    pub fn suggest_state(&self, name: &str) -> Option<&str> {
+        suggest(name, self.states.values().map(|s| s.name.as_str()))
+    }
+
+    /// Suggest an operation name that is similar to a misspelled one.
+    pub fn suggest_op(&self, name: &str) -> Option<&str> {
+        suggest(name, self.ops.values().map(|o| o.name.as_str()))
+    }
+
     /// Generate a filename with an extension appropriate for the given State.
     fn gen_name(&self, stem: &str, state: StateRef) -> Utf8PathBuf {
         let state = &self.states[state];
         if state.is_pseudo() {
             Utf8PathBuf::from(format!("_pseudo_{}", state.name))
         } else {
-            // TODO avoid collisions in case we reuse extensions...
-            Utf8PathBuf::from(stem).with_extension(&state.extensions[0])
+            Utf8PathBuf::from(format!("{}.{}", stem, state.extensions[0]))
         }
     }
 
-    pub fn plan(&self, req: Request) -> Option<Plan> {
-        // Find a path through the states.
-        let path = self.find_path(req.start_state, req.end_state, &req.through)?;
+    /// Generate a filename for a state that is not already in `used`. If the plain name is taken,
+    /// add the state's name to the stem, and then a number.
+    fn unique_name(&self, stem: &str, state: StateRef, used: &HashSet<Utf8PathBuf>) -> Utf8PathBuf {
+        let name = self.gen_name(stem, state);
+        if !used.contains(&name) {
+            return name;
+        }
+        let stem = format!("{}_{}", stem, self.states[state].name);
+        let name = self.gen_name(&stem, state);
+        if !used.contains(&name) {
+            return name;
+        }
+        (2..)
+            .map(|n| self.gen_name(&format!("{}_{}", stem, n), state))
+            .find(|name| !used.contains(name))
+            .expect("some name is unused")
+    }
 
-        let mut steps: Vec<(OpRef, Utf8PathBuf)> = vec![];
+    /// Get the stem of a file in a given state, without the state's extension. This handles
+    /// compound extensions, so `foo.cider.json` has the stem `foo`.
+    fn file_stem<'p>(&self, path: &'p Utf8Path, state: StateRef) -> Option<&'p str> {
+        let name = path.file_name()?;
+        match self.states[state].ext_match(name) {
+            Some(ext) => Some(&name[..name.len() - ext.len() - 1]),
+            None => path.file_stem(),
+        }
+    }
+
+    pub fn plan(&self, req: Request) -> Result<Plan> {
+        if req.targets.is_empty() {
+            return Err(Error::NoTargets);
+        }
+
+        // Avoiding a state means avoiding every operation that consumes or produces it.
+        let mut excluded = req.excluded;
+        for op in &req.avoid_ops {
+            excluded.insert(*op, "on the avoid list".to_string());
+        }
+        for state in &req.avoid_states {
+            for (op_ref, op) in self.ops.iter() {
+                if op.input == *state || op.output == *state {
+                    excluded.entry(op_ref).or_insert_with(|| {
+                        format!("uses avoided state {}", self.states[*state].name)
+                    });
+                }
+            }
+        }
 
         // Get the initial input filename and the stem to use to generate all intermediate filenames.
-        let (stdin, start_file) = match req.start_file {
-            Some(path) => (false, relative_path(&path, &req.workdir)),
-            None => (true, "stdin".into()),
-        };
-        let stem = start_file.file_stem().unwrap();
-
-        // Generate filenames for each step.
-        steps.extend(path.into_iter().map(|op| {
-            let filename = self.gen_name(stem, self.ops[op].output);
-            (op, filename)
-        }));
-
-        // If we have a specified output filename, use that instead of the generated one.
-        let stdout = if let Some(end_file) = req.end_file {
-            // TODO Can we just avoid generating the unused filename in the first place?
-            let last_step = steps.last_mut().expect("no steps");
-            last_step.1 = relative_path(&end_file, &req.workdir);
-            false
-        } else {
-            // Print to stdout if the last state is a real (non-pseudo) state.
-            !self.states[req.end_state].is_pseudo()
+        let (stdin, start_file) = match &req.start_file {
+            Some(path) => (false, relative_path(path, &req.workdir)?),
+            None => (true, self.gen_name("_stdin", req.start_state)),
         };
+        let stem = self
+            .file_stem(&start_file, req.start_state)
+            .ok_or_else(|| Error::BadFilename(start_file.clone()))?;
+
+        // Generated names must not collide with each other or with the input. Targets may end up
+        // in the current directory, so avoid the input's name there too.
+        let mut used = HashSet::from([start_file.clone()]);
+        if let Some(path) = &req.start_file {
+            if path
+                .parent()
+                .is_none_or(|p| p.as_str().is_empty() || p == ".")
+            {
+                used.insert(path.file_name().unwrap_or_default().into());
+            }
+        }
+
+        // Find a path to each target and merge the paths into a tree: targets whose paths start
+        // with the same operations share those steps, so each intermediate file is built once.
+        let mut steps: Vec<Step> = vec![];
+        let mut parents: Vec<Option<usize>> = vec![];
+        let mut target_steps: Vec<Option<usize>> = vec![];
+        let mut skipped = vec![];
+        for target in &req.targets {
+            let path = self
+                .find_path(req.start_state, target.state, &req.through, &excluded)
+                .ok_or_else(|| {
+                    Error::NoPath(self.explain_no_path(
+                        req.start_state,
+                        target.state,
+                        &req.through,
+                        &excluded,
+                    ))
+                })?;
+            for skip in
+                self.excluded_on_path(req.start_state, target.state, &req.through, &excluded)
+            {
+                if !skipped.contains(&skip) {
+                    skipped.push(skip);
+                }
+            }
+
+            let mut cur = None;
+            for op in path {
+                let existing = (0..steps.len()).find(|&i| parents[i] == cur && steps[i].op == op);
+                cur = Some(existing.unwrap_or_else(|| {
+                    let output = self.unique_name(stem, self.ops[op].output, &used);
+                    used.insert(output.clone());
+                    steps.push(Step {
+                        op,
+                        inputs: vec![],
+                        outputs: vec![output],
+                    });
+                    parents.push(cur);
+                    steps.len() - 1
+                }));
+            }
+            if target_steps.contains(&cur) {
+                return Err(Error::DuplicateTarget(
+                    self.states[target.state].name.clone(),
+                ));
+            }
+            target_steps.push(cur);
+        }
+
+        // Decide where each target goes. An explicit filename replaces the generated one. A lone
+        // target without one is printed to stdout, unless it's a pseudo-state. Several targets
+        // are each written to a file in the current directory.
+        let several = req.targets.len() > 1;
+        let mut stdout = false;
+        for (target, step) in req.targets.iter().zip(&target_steps) {
+            let pseudo = self.states[target.state].is_pseudo();
+            match (&target.file, step) {
+                (Some(_), None) => {
+                    return Err(Error::NoPath(format!(
+                        "the input is already in state {}",
+                        self.states[target.state].name
+                    )));
+                }
+                (Some(file), Some(i)) => steps[*i].outputs[0] = relative_path(file, &req.workdir)?,
+                (None, Some(i)) if several && !pseudo => {
+                    steps[*i].outputs[0] = relative_path(steps[*i].output(), &req.workdir)?;
+                }
+                (None, _) => stdout = !several && !pseudo,
+            }
+        }
+
+        // Now that the filenames are settled, connect each step to its input.
+        for i in 0..steps.len() {
+            let input = match parents[i] {
+                Some(parent) => steps[parent].output().to_path_buf(),
+                None => start_file.clone(),
+            };
+            steps[i].inputs = vec![input];
+        }
+        let targets = target_steps
+            .iter()
+            .map(|step| match step {
+                Some(i) => steps[*i].output().to_path_buf(),
+                None => start_file.clone(),
+            })
+            .collect();
 
-        Some(Plan {
+        Ok(Plan {
             start: start_file,
+            start_state: req.start_state,
             steps,
+            targets,
             workdir: req.workdir,
             stdin,
             stdout,
+            through: req.through,
+            avoid_ops: req.avoid_ops,
+            avoid_states: req.avoid_states,
+            skipped,
         })
     }
 
-    pub fn guess_state(&self, path: &Utf8Path) -> Option<StateRef> {
-        let ext = path.extension()?;
+    /// Guess the state of a file from the start of its contents, using the states' sniffing
+    /// functions. The first state that recognizes the contents wins.
+    pub fn sniff_state(&self, contents: &[u8]) -> Option<StateRef> {
+        let head = &contents[..contents.len().min(SNIFF_LEN)];
         self.states
             .iter()
-            .find(|(_, state_data)| state_data.ext_matches(ext))
+            .find(|(_, state)| state.sniff.is_some_and(|sniff| sniff(head)))
+            .map(|(state, _)| state)
+    }
+
+    /// Find the states whose extensions match a filename. When several extensions match, only the
+    /// states with the longest one count, so `foo.cider.json` prefers a `cider.json` state over a
+    /// `json` state.
+    pub fn guess_states(&self, path: &Utf8Path) -> Vec<StateRef> {
+        let Some(name) = path.file_name() else {
+            return vec![];
+        };
+        let matches: Vec<_> = self
+            .states
+            .iter()
+            .filter_map(|(state, data)| Some((state, data.ext_match(name)?.len())))
+            .collect();
+        let longest = matches.iter().map(|(_, len)| *len).max().unwrap_or(0);
+        matches
+            .into_iter()
+            .filter(|(_, len)| *len == longest)
             .map(|(state, _)| state)
+            .collect()
+    }
+
+    /// Guess the state of a file from its extension. It is an error if no state or more than one
+    /// state matches.
+    pub fn guess_state(&self, path: &Utf8Path) -> Result<StateRef> {
+        match self.guess_states(path)[..] {
+            [] => Err(Error::UnknownExtension(path.to_path_buf())),
+            [state] => Ok(state),
+            ref states => Err(Error::AmbiguousState {
+                path: path.to_path_buf(),
+                states: states
+                    .iter()
+                    .map(|s| self.states[*s].name.clone())
+                    .collect(),
+            }),
+        }
     }
 
     pub fn get_state(&self, name: &str) -> Option<StateRef> {
@@ -248,31 +626,189 @@ impl Driver {
     }
 }
 
+/// A mistake in the definition of a driver, found by `DriverBuilder::validate`.
+#[derive(Debug)]
+pub enum Problem {
+    /// Two states have the same name.
+    DuplicateState(String),
+
+    /// Two operations have the same name.
+    DuplicateOp(String),
+
+    /// An operation uses a state that is not part of the driver.
+    MissingState { op: String, state: StateRef },
+
+    /// An operation uses a setup that is not part of the driver.
+    MissingSetup { op: String, setup: SetupRef },
+
+    /// An operation's input is a pseudo-state, which can only be an output.
+    PseudoInput { op: String, state: String },
+
+    /// No operation consumes or produces a state, so it can't be used.
+    UnusedState(String),
+
+    /// Operations use a state, but there's no way to produce it starting from a source state, one
+    /// that no operation produces. It can only be reached from states in a cycle.
+    UnreachableState(String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::DuplicateState(name) => write!(f, "duplicate state name {}", name),
+            Problem::DuplicateOp(name) => write!(f, "duplicate operation name {}", name),
+            Problem::MissingState { op, state } => {
+                write!(f, "operation {} uses unknown state {}", op, state)
+            }
+            Problem::MissingSetup { op, setup } => {
+                write!(f, "operation {} uses unknown setup {}", op, setup)
+            }
+            Problem::PseudoInput { op, state } => {
+                write!(f, "operation {} takes pseudo-state {} as input", op, state)
+            }
+            Problem::UnusedState(name) => write!(f, "state {} is not used by any operation", name),
+            Problem::UnreachableState(name) => {
+                write!(f, "state {} cannot be reached from any source state", name)
+            }
+        }
+    }
+}
+
+/// A function that registers states, operations, and setups with a driver. Add-on crates can
+/// export one of these to extend an existing driver.
+pub type Plugin = fn(&mut DriverBuilder);
+
 pub struct DriverBuilder {
     name: String,
+    version: Option<String>,
     setups: PrimaryMap<SetupRef, Setup>,
     states: PrimaryMap<StateRef, State>,
     ops: PrimaryMap<OpRef, Operation>,
+
+    /// While registering a plugin, the prefix for the names of everything it defines.
+    namespace: Option<String>,
 }
 
 impl DriverBuilder {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
+            version: None,
             setups: Default::default(),
             states: Default::default(),
             ops: Default::default(),
+            namespace: None,
+        }
+    }
+
+    /// The name of the driver being built.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set the driver's version.
+    pub fn version(&mut self, version: &str) {
+        self.version = Some(version.to_string());
+    }
+
+    /// Add the current namespace, if any, to a name.
+    fn qualify(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(ns) => format!("{}.{}", ns, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Look up a state that has already been registered, such as one from the core driver that a
+    /// plugin wants to extend.
+    pub fn find_state(&self, name: &str) -> Option<StateRef> {
+        self.states
+            .iter()
+            .find(|(_, state)| state.name == name)
+            .map(|(state, _)| state)
+    }
+
+    /// Look up a setup that has already been registered, so a plugin can reuse it.
+    pub fn find_setup(&self, name: &str) -> Option<SetupRef> {
+        self.setups
+            .iter()
+            .find(|(_, setup)| setup.name == name)
+            .map(|(setup, _)| setup)
+    }
+
+    /// Register a plugin. The states, operations, and setups it defines get names prefixed with
+    /// `namespace.` to keep them from clashing with anyone else's. The plugin can use the
+    /// existing states and setups, which it can find by name with `find_state` and
+    /// `find_setup`. Ninja rule names are not namespaced, so plugins should choose distinctive
+    /// ones.
+    pub fn plugin<R>(&mut self, namespace: &str, register: impl FnOnce(&mut Self) -> R) -> R {
+        let outer = self.namespace.replace(namespace.to_string());
+        let res = register(self);
+        self.namespace = outer;
+        res
+    }
+
+    /// Import all the states, operations, and setups from another driver. States whose names
+    /// already exist here are shared, which is how the other driver's operations connect to ours.
+    /// Setups with existing names are shared too, so their rules are only emitted once. Everything
+    /// else gets a name prefixed with `namespace.`.
+    pub fn merge(&mut self, namespace: &str, other: DriverBuilder) {
+        let outer = self.namespace.replace(namespace.to_string());
+        let mut states = HashMap::new();
+        for (old, state) in other.states {
+            let new = match self.find_state(&state.name) {
+                Some(existing) => existing,
+                None => self.states.push(State {
+                    name: self.qualify(&state.name),
+                    ..state
+                }),
+            };
+            states.insert(old, new);
+        }
+        let mut setups = HashMap::new();
+        for (old, setup) in other.setups {
+            let new = match self.find_setup(&setup.name) {
+                Some(existing) => existing,
+                None => self.add_setup_boxed(&setup.name, setup.emit),
+            };
+            setups.insert(old, new);
         }
+        for (_, op) in other.ops {
+            // References to states and setups that the other driver didn't have stay invalid, so
+            // validation catches them.
+            let op_setups: Vec<_> = op
+                .setups
+                .iter()
+                .map(|s| *setups.get(s).unwrap_or(s))
+                .collect();
+            let input = *states.get(&op.input).unwrap_or(&op.input);
+            let output = *states.get(&op.output).unwrap_or(&op.output);
+            self.ops.push(Operation {
+                name: self.qualify(&op.name),
+                input,
+                output,
+                setups: op_setups,
+                emit: op.emit,
+                requires: op.requires,
+            });
+        }
+        self.namespace = outer;
     }
 
     pub fn state(&mut self, name: &str, extensions: &[&str]) -> StateRef {
         self.states.push(State {
-            name: name.to_string(),
+            name: self.qualify(name),
             extensions: extensions.iter().map(|s| s.to_string()).collect(),
+            sniff: None,
         })
     }
 
-    fn add_op<T: run::EmitBuild + 'static>(
+    /// Register a function to recognize files in a state from their contents.
+    pub fn sniff(&mut self, state: StateRef, sniff: SniffFn) {
+        self.states[state].sniff = Some(sniff);
+    }
+
+    pub(crate) fn add_op<T: run::EmitBuild + 'static>(
         &mut self,
         name: &str,
         setups: &[SetupRef],
@@ -281,18 +817,23 @@ impl DriverBuilder {
         emit: T,
     ) -> OpRef {
         self.ops.push(Operation {
-            name: name.into(),
+            name: self.qualify(name),
             setups: setups.into(),
             input,
             output,
             emit: Box::new(emit),
+            requires: vec![],
         })
     }
 
     pub fn add_setup<T: run::EmitSetup + 'static>(&mut self, name: &str, emit: T) -> SetupRef {
+        self.add_setup_boxed(name, Box::new(emit))
+    }
+
+    fn add_setup_boxed(&mut self, name: &str, emit: Box<dyn run::EmitSetup>) -> SetupRef {
         self.setups.push(Setup {
-            name: name.into(),
-            emit: Box::new(emit),
+            name: self.qualify(name),
+            emit,
         })
     }
 
@@ -329,45 +870,196 @@ impl DriverBuilder {
         )
     }
 
-    pub fn build(self) -> Driver {
-        Driver {
+    /// Make an operation available only when all the given configuration keys are set. This lets
+    /// the planner route around operations for tools that aren't installed.
+    pub fn require_config(&mut self, op: OpRef, keys: &[&str]) {
+        self.ops[op]
+            .requires
+            .extend(keys.iter().map(|k| k.to_string()));
+    }
+
+    /// Check the driver for mistakes, returning all the problems found.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+
+        // Names must be unique, because we look up states and ops by name.
+        let mut seen = HashSet::new();
+        let mut dupes = BTreeSet::new();
+        for state in self.states.values() {
+            if !seen.insert(&state.name) {
+                dupes.insert(&state.name);
+            }
+        }
+        problems.extend(dupes.into_iter().cloned().map(Problem::DuplicateState));
+        let mut seen = HashSet::new();
+        let mut dupes = BTreeSet::new();
+        for op in self.ops.values() {
+            if !seen.insert(&op.name) {
+                dupes.insert(&op.name);
+            }
+        }
+        problems.extend(dupes.into_iter().cloned().map(Problem::DuplicateOp));
+
+        // Operations must refer to states and setups in this driver.
+        let mut used_states = HashSet::new();
+        for op in self.ops.values() {
+            for state in [op.input, op.output] {
+                if self.states.is_valid(state) {
+                    used_states.insert(state);
+                } else {
+                    problems.push(Problem::MissingState {
+                        op: op.name.clone(),
+                        state,
+                    });
+                }
+            }
+            for setup in &op.setups {
+                if !self.setups.is_valid(*setup) {
+                    problems.push(Problem::MissingSetup {
+                        op: op.name.clone(),
+                        setup: *setup,
+                    });
+                }
+            }
+            if self.states.get(op.input).is_some_and(|s| s.is_pseudo()) {
+                problems.push(Problem::PseudoInput {
+                    op: op.name.clone(),
+                    state: self.states[op.input].name.clone(),
+                });
+            }
+        }
+
+        // Every state should be the input or output of some operation.
+        for (state_ref, state) in self.states.iter() {
+            if !used_states.contains(&state_ref) {
+                problems.push(Problem::UnusedState(state.name.clone()));
+            }
+        }
+
+        // Every used state should be reachable from a state that no operation produces.
+        let ops: Vec<_> = self
+            .ops
+            .values()
+            .filter(|op| self.states.is_valid(op.input) && self.states.is_valid(op.output))
+            .collect();
+        let mut reached: HashSet<StateRef> = used_states
+            .iter()
+            .copied()
+            .filter(|&state| !ops.iter().any(|op| op.output == state))
+            .collect();
+        let mut queue: Vec<StateRef> = reached.iter().copied().collect();
+        while let Some(state) = queue.pop() {
+            for op in ops.iter().filter(|op| op.input == state) {
+                if reached.insert(op.output) {
+                    queue.push(op.output);
+                }
+            }
+        }
+        for (state_ref, state) in self.states.iter() {
+            if used_states.contains(&state_ref) && !reached.contains(&state_ref) {
+                problems.push(Problem::UnreachableState(state.name.clone()));
+            }
+        }
+
+        problems
+    }
+
+    /// Finish building the driver, checking it for mistakes.
+    pub fn build(self) -> Result<Driver> {
+        let problems = self.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidDriver(problems));
+        }
+        Ok(Driver {
             name: self.name,
+            version: self.version,
             setups: self.setups,
             states: self.states,
             ops: self.ops,
-        }
+        })
     }
 }
 
 /// A request to the Driver directing it what to build.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     /// The input format.
     pub start_state: StateRef,
 
-    /// The output format to produce.
-    pub end_state: StateRef,
+    /// The outputs to produce.
+    pub targets: Vec<Target>,
 
     /// The filename to read the input from, or None to read from stdin.
     pub start_file: Option<Utf8PathBuf>,
 
-    /// The filename to write the output to, or None to print to stdout.
-    pub end_file: Option<Utf8PathBuf>,
-
     /// A sequence of operators to route the conversion through.
     pub through: Vec<OpRef>,
 
     /// The working directory for the build.
     pub workdir: Utf8PathBuf,
+
+    /// Operations that the plan must not use, with the reason for each.
+    pub excluded: HashMap<OpRef, String>,
+
+    /// Operations to keep out of the conversion.
+    pub avoid_ops: Vec<OpRef>,
+
+    /// States to keep out of the conversion, along with every operation that uses them.
+    pub avoid_states: Vec<StateRef>,
 }
 
-#[derive(Debug)]
+/// An output that a request asks for.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Target {
+    /// The state to produce.
+    pub state: StateRef,
+
+    /// The filename to write the output to. Without one, a lone target is printed to stdout and
+    /// several targets are each written to a file in the current directory.
+    pub file: Option<Utf8PathBuf>,
+}
+
+/// One operation in a plan, with the files it consumes and produces. These files are the edges
+/// between steps: a step depends on the steps that produce its inputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub op: OpRef,
+
+    /// The files the step reads. The first is in the operation's input state; any others are
+    /// extra inputs, like data files, that the operation knows how to use.
+    pub inputs: Vec<Utf8PathBuf>,
+
+    /// The files the step writes. The first is in the operation's output state.
+    pub outputs: Vec<Utf8PathBuf>,
+}
+
+impl Step {
+    /// The step's main input, in the operation's input state.
+    pub fn input(&self) -> Option<&Utf8Path> {
+        self.inputs.first().map(Utf8PathBuf::as_path)
+    }
+
+    /// The step's main output, in the operation's output state.
+    pub fn output(&self) -> &Utf8Path {
+        &self.outputs[0]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plan {
     /// The input to the first step.
     pub start: Utf8PathBuf,
 
-    /// The chain of operations to run and each step's output file.
-    pub steps: Vec<(OpRef, Utf8PathBuf)>,
+    /// The state of the input file.
+    pub start_state: StateRef,
+
+    /// The operations to run. They form a DAG, connected by the files they consume and produce,
+    /// so a file can feed several steps and a step can combine several files. Use
+    /// `topological_order` to visit the steps so that dependencies come first.
+    pub steps: Vec<Step>,
+
+    /// The file for each requested target, in the order they were requested.
+    pub targets: Vec<Utf8PathBuf>,
 
     /// The directory that the build will happen in.
     pub workdir: Utf8PathBuf,
@@ -375,15 +1067,375 @@ pub struct Plan {
     /// Read the first input from stdin.
     pub stdin: bool,
 
-    /// Write the final output to stdout.
+    /// Write the final output to stdout. This only happens when there is a single target.
     pub stdout: bool,
+
+    /// The operations that the plan was asked to route through.
+    pub through: Vec<OpRef>,
+
+    /// The operations and states that the plan was asked to avoid.
+    pub avoid_ops: Vec<OpRef>,
+    pub avoid_states: Vec<StateRef>,
+
+    /// Excluded operations that the plan would otherwise have used, with the reason for each.
+    pub skipped: Vec<(OpRef, String)>,
 }
 
 impl Plan {
-    pub fn end(&self) -> &Utf8Path {
-        match self.steps.last() {
-            Some((_, path)) => path,
-            None => &self.start,
+    /// The file for the first target, which is the only one when the plan prints to stdout. Only
+    /// a hand-built plan can have no targets.
+    pub fn end(&self) -> Option<&Utf8Path> {
+        self.targets.first().map(Utf8PathBuf::as_path)
+    }
+
+    /// Find the steps that produce the inputs of step `index`.
+    pub fn dependencies(&self, index: usize) -> Vec<usize> {
+        let step = &self.steps[index];
+        (0..self.steps.len())
+            .filter(|&i| {
+                self.steps[i]
+                    .outputs
+                    .iter()
+                    .any(|out| step.inputs.contains(out))
+            })
+            .collect()
+    }
+
+    /// Order the steps so that each comes after all the steps it depends on. Steps that don't
+    /// depend on each other stay in their original order. It is an error for the steps to depend
+    /// on each other in a cycle.
+    pub fn topological_order(&self) -> Result<Vec<usize>> {
+        let deps: Vec<_> = (0..self.steps.len())
+            .map(|i| self.dependencies(i))
+            .collect();
+        let mut done = vec![false; self.steps.len()];
+        let mut order = vec![];
+        while order.len() < self.steps.len() {
+            let next = (0..self.steps.len())
+                .find(|&i| !done[i] && deps[i].iter().all(|&d| done[d]))
+                .ok_or(Error::CyclicPlan)?;
+            done[next] = true;
+            order.push(next);
+        }
+        Ok(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::request;
+
+    /// A driver that converts `a` to `b` and `b` to `c`.
+    fn driver() -> Driver {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], b, c, "b_to_c");
+        bld.build().unwrap()
+    }
+
+    #[test]
+    fn longest_extension_wins() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let json = bld.state("json", &["json"]);
+        let cider = bld.state("cider", &["cider.json"]);
+        let v = bld.state("v", &["v"]);
+        let sv = bld.state("sv", &["v"]);
+        bld.rule(&[], json, cider, "json_to_cider");
+        bld.rule(&[], v, sv, "v_to_sv");
+        let driver = bld.build().unwrap();
+
+        assert_eq!(driver.guess_state("x.cider.json".into()).unwrap(), cider);
+        assert_eq!(driver.guess_state("x.json".into()).unwrap(), json);
+        assert!(matches!(
+            driver.guess_state("x.v".into()),
+            Err(Error::AmbiguousState { states, .. }) if states == ["v", "sv"]
+        ));
+        assert!(matches!(
+            driver.guess_state("x.txt".into()),
+            Err(Error::UnknownExtension(_))
+        ));
+        assert_eq!(driver.file_stem("x.cider.json".into(), cider), Some("x"));
+    }
+
+    #[test]
+    fn generated_names_avoid_the_input() {
+        // Both `a` and `c` files end in `.a`, so `c` needs a different name from the input.
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["a"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], b, c, "b_to_c");
+        let driver = bld.build().unwrap();
+
+        let mut req = request(&driver, "in.b", &["out.b"], Utf8Path::new("."));
+        req.start_state = a;
+        req.start_file = Some("in.a".into());
+        req.targets = vec![Target {
+            state: c,
+            file: None,
+        }];
+        let plan = driver.plan(req).unwrap();
+        let outputs: Vec<_> = plan.steps.iter().map(|s| s.output().as_str()).collect();
+        assert_eq!(outputs, ["in.b", "in_c.a"]);
+        assert!(plan.stdout);
+    }
+
+    #[test]
+    fn sniffing_picks_the_first_match() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.sniff(a, |head| head.starts_with(b"A"));
+        bld.sniff(b, |head| head.starts_with(b"AB"));
+        let driver = bld.build().unwrap();
+
+        assert_eq!(driver.sniff_state(b"AB..."), Some(a));
+        assert_eq!(driver.sniff_state(b"B..."), None);
+    }
+
+    #[test]
+    fn validation_finds_every_problem() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        bld.state("a", &["a2"]);
+        let pseudo = bld.state("pseudo", &[]);
+        bld.state("unused", &["u"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let d = bld.state("d", &["d"]);
+        bld.rule(&[], a, pseudo, "dup");
+        bld.rule(&[], a, d, "dup");
+        bld.rule(&[], pseudo, d, "from_pseudo");
+        bld.rule(&[], b, c, "b_to_c");
+        bld.rule(&[], c, b, "c_to_b");
+
+        let problems: Vec<_> = bld.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            [
+                "duplicate state name a",
+                "duplicate operation name dup",
+                "operation from_pseudo takes pseudo-state pseudo as input",
+                "state a is not used by any operation",
+                "state unused is not used by any operation",
+                "state b cannot be reached from any source state",
+                "state c cannot be reached from any source state",
+            ]
+        );
+        let err = bld.build().err().unwrap().to_string();
+        assert!(err.starts_with("invalid driver:\n  duplicate state name a\n"));
+    }
+
+    #[test]
+    fn merged_drivers_share_states_and_setups() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let tools = bld.setup("tools", |_| Ok(()));
+        bld.rule(&[tools], a, b, "a_to_b");
+
+        let mut other = DriverBuilder::new("other");
+        let other_b = other.state("b", &["b"]);
+        let x = other.state("x", &["x"]);
+        let other_tools = other.setup("tools", |_| Ok(()));
+        let extra = other.setup("extra", |_| Ok(()));
+        other.rule(&[other_tools, extra], other_b, x, "b_to_x");
+        bld.merge("other", other);
+
+        bld.plugin("plug", |bld| {
+            let y = bld.state("y", &["y"]);
+            let x = bld.find_state("other.x").unwrap();
+            bld.rule(&[], x, y, "x_to_y");
+        });
+        let driver = bld.build().unwrap();
+
+        let names = |names: Vec<&str>| names.join(" ");
+        assert_eq!(
+            names(driver.states.values().map(|s| s.name.as_str()).collect()),
+            "a b other.x plug.y"
+        );
+        assert_eq!(
+            names(driver.setups.values().map(|s| s.name.as_str()).collect()),
+            "tools other.extra"
+        );
+        let op = &driver.ops[driver.get_op("other.b_to_x").unwrap()];
+        assert_eq!(op.input, b);
+        assert_eq!(op.setups[0], tools);
+        assert!(driver.get_op("plug.x_to_y").is_some());
+        crate::testing::assert_path(&driver, "a", "plug.y");
+    }
+
+    #[test]
+    fn plans_route_around_unavailable_ops() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let d = bld.state("d", &["d"]);
+        let fast = bld.rule(&[], a, c, "fast");
+        bld.require_config(fast, &["tool.fast"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], b, c, "b_to_c");
+        let only = bld.rule(&[], c, d, "only");
+        bld.require_config(only, &["tool.only", "tool.path"]);
+        let driver = bld.build().unwrap();
+
+        let config = crate::testing::default_config();
+        let excluded = driver.unavailable_ops(&config);
+        assert_eq!(excluded[&fast], "missing config key tool.fast");
+        assert_eq!(excluded[&only], "missing config keys tool.only, tool.path");
+
+        let mut req = request(&driver, "in.a", &["out.c"], Utf8Path::new("."));
+        req.excluded = excluded.clone();
+        let plan = driver.plan(req).unwrap();
+        let ops: Vec<_> = plan.steps.iter().map(|s| &driver.ops[s.op].name).collect();
+        assert_eq!(ops, ["a_to_b", "b_to_c"]);
+        assert_eq!(
+            plan.skipped,
+            [(fast, "missing config key tool.fast".into())]
+        );
+
+        let mut req = request(&driver, "in.a", &["out.d"], Utf8Path::new("."));
+        req.excluded = excluded;
+        match driver.plan(req) {
+            Err(Error::NoPath(msg)) => assert_eq!(
+                msg,
+                "could not find a path from a to d\n\
+                 fast is excluded: missing config key tool.fast\n\
+                 only is excluded: missing config keys tool.only, tool.path"
+            ),
+            _ => panic!("expected no path"),
+        }
+
+        let config = config.merge(figment::providers::Serialized::default("tool.fast", "f"));
+        assert!(!driver.unavailable_ops(&config).contains_key(&fast));
+    }
+
+    #[test]
+    fn plans_avoid_ops_and_states() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let d = bld.state("d", &["d"]);
+        let a_to_b = bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], a, c, "a_to_c");
+        let b_to_d = bld.rule(&[], b, d, "b_to_d");
+        bld.rule(&[], c, d, "c_to_d");
+        let driver = bld.build().unwrap();
+        let plan = |avoid_ops: Vec<OpRef>, avoid_states: Vec<StateRef>| {
+            let mut req = request(&driver, "in.a", &["out.d"], Utf8Path::new("."));
+            req.avoid_ops = avoid_ops;
+            req.avoid_states = avoid_states;
+            driver.plan(req)
+        };
+        let ops = |plan: &Plan| -> Vec<String> {
+            plan.steps
+                .iter()
+                .map(|s| driver.ops[s.op].name.clone())
+                .collect()
+        };
+
+        let avoided = plan(vec![a_to_b], vec![]).unwrap();
+        assert_eq!(ops(&avoided), ["a_to_c", "c_to_d"]);
+        assert_eq!(avoided.avoid_ops, [a_to_b]);
+        assert_eq!(avoided.skipped, [(a_to_b, "on the avoid list".into())]);
+
+        let avoided = plan(vec![], vec![b]).unwrap();
+        assert_eq!(ops(&avoided), ["a_to_c", "c_to_d"]);
+        assert_eq!(
+            avoided.skipped,
+            [
+                (a_to_b, "uses avoided state b".into()),
+                (b_to_d, "uses avoided state b".into())
+            ]
+        );
+
+        // An op on the avoid list keeps its own reason even if it also uses an avoided state.
+        let Err(Error::NoPath(msg)) = plan(vec![b_to_d], vec![b, c]) else {
+            panic!("expected no path");
+        };
+        assert_eq!(
+            msg,
+            "could not find a path from a to d\n\
+             a_to_b is excluded: uses avoided state b\n\
+             b_to_d is excluded: on the avoid list"
+        );
+    }
+
+    #[test]
+    fn targets_share_common_steps() {
+        let mut bld = DriverBuilder::new("drivertest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let d = bld.state("d", &["d"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], b, c, "b_to_c");
+        bld.rule(&[], b, d, "b_to_d");
+        let driver = bld.build().unwrap();
+
+        let req = request(&driver, "in.a", &["out.c", "out.d"], Utf8Path::new("."));
+        let plan = driver.plan(req).unwrap();
+        let steps: Vec<_> = plan
+            .steps
+            .iter()
+            .map(|s| format!("{} -> {}", s.inputs[0], s.outputs[0]))
+            .collect();
+        assert_eq!(steps, ["in.a -> in.b", "in.b -> out.c", "in.b -> out.d"]);
+        assert_eq!(plan.targets, ["out.c", "out.d"]);
+        assert_eq!(plan.dependencies(2), [0]);
+        assert!(!plan.stdout);
+
+        // Several targets without filenames each get a file in the current directory.
+        let mut req = request(&driver, "in.a", &["out.c", "out.d"], Utf8Path::new("build"));
+        for target in &mut req.targets {
+            target.file = None;
         }
+        let plan = driver.plan(req).unwrap();
+        assert_eq!(plan.targets, ["../in.c", "../in.d"]);
+
+        let req = request(&driver, "in.a", &["x.c", "y.c"], Utf8Path::new("."));
+        assert!(matches!(
+            driver.plan(req),
+            Err(Error::DuplicateTarget(state)) if state == "c"
+        ));
+    }
+
+    #[test]
+    fn steps_are_ordered_by_their_files() {
+        let driver = driver();
+        let step = |op: &str, inputs: &[&str], outputs: &[&str]| Step {
+            op: driver.get_op(op).unwrap(),
+            inputs: inputs.iter().map(|&f| f.into()).collect(),
+            outputs: outputs.iter().map(|&f| f.into()).collect(),
+        };
+        let req = request(&driver, "in.a", &["out.c"], Utf8Path::new("."));
+        let mut plan = driver.plan(req).unwrap();
+
+        // A step that combines two files comes after both of the steps that produce them.
+        plan.steps = vec![
+            step("b_to_c", &["x.b", "y.b"], &["out.c"]),
+            step("a_to_b", &["in.a"], &["x.b"]),
+            step("a_to_b", &["in.a"], &["y.b"]),
+        ];
+        assert_eq!(plan.dependencies(0), [1, 2]);
+        assert_eq!(plan.topological_order().unwrap(), [1, 2, 0]);
+
+        plan.steps[1].inputs = vec!["out.c".into()];
+        assert!(matches!(plan.topological_order(), Err(Error::CyclicPlan)));
+    }
+
+    #[test]
+    fn empty_targets_are_rejected() {
+        let driver = driver();
+        let req = request(&driver, "in.a", &[], Utf8Path::new("."));
+        assert!(matches!(driver.plan(req), Err(Error::NoTargets)));
     }
 }
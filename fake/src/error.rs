@@ -0,0 +1,223 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fmt::Display;
+
+/// An error that arises while configuring, planning, emitting, or executing a build.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error, possibly associated with a specific file.
+    Io {
+        path: Option<Utf8PathBuf>,
+        err: std::io::Error,
+    },
+
+    /// The configuration could not be loaded, e.g., because the file is malformed.
+    Config(Box<figment::Error>),
+
+    /// There is no place to look for a configuration file.
+    NoConfigDir,
+
+    /// A configuration value that the build requires is not set.
+    MissingConfig(String),
+
+    /// A filename that can't be used in the build, e.g., because it has no stem.
+    BadFilename(Utf8PathBuf),
+
+    /// No state has the extension of a file.
+    UnknownExtension(Utf8PathBuf),
+
+    /// Several states have the extension of a file, so we can't tell which one it is in.
+    AmbiguousState {
+        path: Utf8PathBuf,
+        states: Vec<String>,
+    },
+
+    /// No chain of operations can produce the requested output. The string explains why.
+    NoPath(String),
+
+    /// A request asks for the same state more than once.
+    DuplicateTarget(String),
+
+    /// A request asks for no states at all.
+    NoTargets,
+
+    /// The steps in a plan depend on each other in a cycle.
+    CyclicPlan,
+
+    /// A plan step gives an operation extra input or output files that it can't use.
+    ExtraFiles(Vec<String>),
+
+    /// Something went wrong while emitting the Ninja code for a setup.
+    Setup { name: String, err: Box<Error> },
+
+    /// Something went wrong while emitting the Ninja code for an operation.
+    Op { name: String, err: Box<Error> },
+
+    /// The driver itself is not well formed.
+    InvalidDriver(Vec<crate::driver::Problem>),
+
+    /// A plugin executable printed something we couldn't understand.
+    PluginResponse {
+        path: Utf8PathBuf,
+        err: serde_json::Error,
+    },
+
+    /// A plugin's manifest refers to a state or setup (`what`) that doesn't exist.
+    PluginReference {
+        path: Utf8PathBuf,
+        what: String,
+        name: String,
+    },
+
+    /// A build recipe file could not be parsed.
+    BadRecipe {
+        path: Utf8PathBuf,
+        err: serde_json::Error,
+    },
+
+    /// A build recipe can't be replayed with this driver, e.g., because it uses an operation that
+    /// no longer exists. The string explains why.
+    RecipeMismatch(String),
+
+    /// A directory that we were asked to clean up is not a build directory.
+    NotWorkdir(Utf8PathBuf),
+
+    /// A command failed while executing the build.
+    Exec {
+        cmd: String,
+        status: std::process::ExitStatus,
+    },
+}
+
+impl Error {
+    /// Wrap an I/O error with the path of the file involved. Use like `.map_err(Error::at(path))`.
+    pub(crate) fn at(path: &Utf8Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |err| Self::Io {
+            path: Some(path.to_path_buf()),
+            err,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io { path: None, err }
+    }
+}
+
+impl From<figment::Error> for Error {
+    fn from(e: figment::Error) -> Self {
+        Self::Config(Box::new(e))
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        std::io::Error::other(e).into()
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            Error::Io { path: Some(p), .. } => write!(f, "could not access {}", p),
+            Error::Io { path: None, err } => write!(f, "{}", err),
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            Error::NoConfigDir => write!(f, "neither $XDG_CONFIG_HOME nor $HOME is set"),
+            Error::MissingConfig(s) => write!(f, "missing required config key: {}", s),
+            Error::BadFilename(p) => write!(f, "unusable filename: {}", p),
+            Error::UnknownExtension(p) => write!(f, "could not infer the state of {}", p),
+            Error::AmbiguousState { path, states } => write!(
+                f,
+                "could not infer the state of {}: it could be {}",
+                path,
+                states.join(" or ")
+            ),
+            Error::NoPath(s) => write!(f, "{}", s),
+            Error::CyclicPlan => write!(f, "the plan's steps depend on each other in a cycle"),
+            Error::DuplicateTarget(s) => write!(f, "state {} is requested more than once", s),
+            Error::NoTargets => write!(f, "no target state was requested"),
+            Error::ExtraFiles(files) => {
+                write!(f, "cannot use the extra files {}", files.join(", "))
+            }
+            Error::Setup { name, .. } => write!(f, "in setup {}", name),
+            Error::Op { name, .. } => write!(f, "in operation {}", name),
+            Error::InvalidDriver(problems) => {
+                write!(f, "invalid driver:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            Error::PluginResponse { path, .. } => {
+                write!(f, "plugin {} sent an invalid response", path)
+            }
+            Error::PluginReference { path, what, name } => {
+                write!(f, "plugin {} refers to unknown {} {}", path, what, name)
+            }
+            Error::BadRecipe { path, .. } => write!(f, "{} is not a valid build recipe", path),
+            Error::RecipeMismatch(s) => write!(f, "{}", s),
+            Error::NotWorkdir(p) => write!(
+                f,
+                "refusing to remove {}, which is not a build directory",
+                p
+            ),
+            Error::Exec { cmd, status } => write!(f, "{} failed ({})", cmd, status),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self {
+            Error::Io { path: Some(_), err } => Some(err),
+            Error::Setup { err, .. } | Error::Op { err, .. } => Some(err.as_ref()),
+            Error::PluginResponse { err, .. } | Error::BadRecipe { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::DriverBuilder;
+    use crate::run::Run;
+    use crate::testing::{default_config, request, TempDir};
+
+    /// The messages for an error and each of its causes.
+    fn chain(err: Error) -> Vec<String> {
+        anyhow::Error::from(err)
+            .chain()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn io_errors_name_the_file() {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        assert_eq!(
+            chain(Error::at("in.a".into())(err)),
+            ["could not access in.a", "no such file"]
+        );
+    }
+
+    #[test]
+    fn setup_errors_keep_their_cause() {
+        let mut bld = DriverBuilder::new("errortest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let tools = bld.setup("tools", |e| e.config_var("tool", "tool.path"));
+        bld.rule(&[tools], a, b, "a_to_b");
+        let driver = bld.build().unwrap();
+
+        let tmp = TempDir::new("error-setup");
+        let req = request(&driver, "in.a", &["out.b"], &tmp.0);
+        let run = Run::with_config(&driver, driver.plan(req).unwrap(), default_config()).unwrap();
+        assert_eq!(
+            chain(run.emit_to_dir(&tmp.0).unwrap_err()),
+            ["in setup tools", "missing required config key: tool.path"]
+        );
+    }
+}
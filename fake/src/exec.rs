@@ -0,0 +1,510 @@
+use crate::driver::{OpRef, Step};
+use crate::error::{Error, Result};
+use crate::report::{self, LogEntry};
+use crate::run::Run;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Something that happens while executing a plan. Steps are identified by their index in the
+/// plan.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PlanStarted {
+        steps: usize,
+    },
+    StepStarted {
+        step: usize,
+        op: String,
+        file: Utf8PathBuf,
+    },
+    /// A line of output from the build tool, without the trailing newline.
+    Output {
+        step: usize,
+        line: String,
+        stderr: bool,
+    },
+    StepFinished {
+        step: usize,
+        op: String,
+        seconds: f64,
+    },
+    StepFailed {
+        step: usize,
+        op: String,
+        code: Option<i32>,
+    },
+    PlanFinished {
+        success: bool,
+        seconds: f64,
+    },
+}
+
+/// Receives events while a plan executes.
+pub trait Observer {
+    fn event(&mut self, event: &Event);
+}
+
+/// An observer that ignores all events.
+impl Observer for () {
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Collect all the events for later inspection.
+impl Observer for Vec<Event> {
+    fn event(&mut self, event: &Event) {
+        self.push(event.clone());
+    }
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// An observer that shows a progress bar for the plan's steps on stderr. Tool output is shown
+/// only when a step fails, unless `verbose` is set.
+pub struct TermReporter {
+    verbose: bool,
+    steps: usize,
+
+    /// The number of steps that have finished. Steps may not run in index order, so we count.
+    done: usize,
+    tty: bool,
+    lines: Vec<String>,
+}
+
+impl TermReporter {
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            verbose,
+            steps: 0,
+            done: 0,
+            tty: std::io::stderr().is_terminal(),
+            lines: vec![],
+        }
+    }
+
+    /// Draw the progress bar for the `count`th step, where `done` steps have completed.
+    fn bar(&self, done: usize, count: usize, op: &str) -> String {
+        const WIDTH: usize = 20;
+        let filled = WIDTH * done / self.steps.max(1);
+        format!(
+            "[{}{}] {}/{} {}",
+            "#".repeat(filled),
+            "-".repeat(WIDTH - filled),
+            count + 1,
+            self.steps,
+            op
+        )
+    }
+
+    fn report(&mut self, event: &Event) -> std::io::Result<()> {
+        let mut err = std::io::stderr().lock();
+        match event {
+            Event::PlanStarted { steps } => {
+                self.steps = *steps;
+                self.done = 0;
+                Ok(())
+            }
+            Event::StepStarted { op, .. } => {
+                self.lines.clear();
+                if self.tty {
+                    write!(err, "\r\x1b[K{}", self.bar(self.done, self.done, op))
+                } else {
+                    writeln!(err, "{}", self.bar(self.done, self.done, op))
+                }
+            }
+            Event::Output { line, .. } => {
+                if self.verbose {
+                    if self.tty {
+                        write!(err, "\r\x1b[K")?;
+                    }
+                    writeln!(err, "{}", line)
+                } else {
+                    self.lines.push(line.clone());
+                    Ok(())
+                }
+            }
+            Event::StepFinished { op, seconds, .. } => {
+                if self.tty {
+                    write!(err, "\r\x1b[K")?;
+                }
+                self.done += 1;
+                let bar = self.bar(self.done, self.done - 1, op);
+                writeln!(err, "{} ({:.2}s)", bar, seconds)
+            }
+            Event::StepFailed { op, .. } => {
+                if self.tty {
+                    write!(err, "\r\x1b[K")?;
+                }
+                for line in &self.lines {
+                    writeln!(err, "{}", line)?;
+                }
+                writeln!(err, "{} failed", self.bar(self.done, self.done, op))
+            }
+            Event::PlanFinished { .. } => Ok(()),
+        }
+    }
+}
+
+impl Observer for TermReporter {
+    fn event(&mut self, event: &Event) {
+        // Progress is best-effort, so we ignore errors writing to stderr.
+        let _ = self.report(event);
+    }
+}
+
+/// The final output of an execution.
+#[derive(Debug)]
+pub enum Output {
+    /// The output was written to a file, whose path is relative to the current directory.
+    File(Utf8PathBuf),
+
+    /// The output that would have gone to stdout.
+    Bytes(Vec<u8>),
+
+    /// There is no output, because the plan ends in a pseudo-state, a step failed, or this was a
+    /// dry run.
+    None,
+}
+
+/// The outcome of executing one step in a plan.
+#[derive(Debug)]
+pub struct StepResult {
+    /// The operation that this step runs.
+    pub op: OpRef,
+
+    /// The step's output file, relative to the working directory.
+    pub file: Utf8PathBuf,
+
+    /// The exit status of the build tool.
+    pub status: ExitStatus,
+
+    /// Everything that the build printed to stdout while executing this step.
+    pub stdout: Vec<u8>,
+
+    /// Everything that the build printed to stderr while executing this step.
+    pub stderr: Vec<u8>,
+
+    /// The wall-clock time taken by the step.
+    pub duration: Duration,
+
+    /// The commands that Ninja ran for this step, from its log. This is empty if the step's output
+    /// was already up to date.
+    pub commands: Vec<LogEntry>,
+}
+
+/// The outcome of executing an entire plan.
+#[derive(Debug)]
+pub struct RunResult {
+    /// The exit status of the last step that ran, which is a failure if any step failed.
+    pub status: ExitStatus,
+
+    /// The plan's final output.
+    pub output: Output,
+
+    /// The results for each step that ran, in order. Steps after a failed one do not run.
+    pub steps: Vec<StepResult>,
+
+    /// The wall-clock time for the entire execution.
+    pub duration: Duration,
+}
+
+impl RunResult {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+}
+
+impl<'a> Run<'a> {
+    /// Emit `build.ninja` to `dir` and execute the plan, one step at a time, capturing everything
+    /// instead of printing to the terminal. If the plan reads from stdin, the input is `input` if
+    /// it's provided or the process's stdin otherwise.
+    ///
+    /// A failing step is not an `Err`; instead, it produces a `RunResult` with a failing status.
+    /// The `observer` receives events as the execution progresses.
+    pub fn execute(
+        &self,
+        dir: &Utf8Path,
+        input: Option<&[u8]>,
+        observer: &mut dyn Observer,
+    ) -> Result<RunResult> {
+        let start_time = Instant::now();
+        let stale_dir = dir.exists();
+        self.emit_to_dir(dir)?;
+        if self.plan.stdin {
+            self.capture_stdin(input)?;
+        }
+
+        // Ask Ninja to build each step's output in turn, so we can attribute output and time to
+        // steps. Earlier steps are already up to date when we get to later ones.
+        observer.event(&Event::PlanStarted {
+            steps: self.plan.steps.len(),
+        });
+        let mut steps = vec![];
+        let mut status = ExitStatus::default();
+        for index in self.plan.topological_order()? {
+            let plan_step = &self.plan.steps[index];
+            let op_name = &self.driver.ops[plan_step.op].name;
+            observer.event(&Event::StepStarted {
+                step: index,
+                op: op_name.clone(),
+                file: plan_step.output().to_path_buf(),
+            });
+            let step = self.execute_step(dir, index, plan_step, observer)?;
+            status = step.status;
+            if status.success() {
+                observer.event(&Event::StepFinished {
+                    step: index,
+                    op: op_name.clone(),
+                    seconds: step.duration.as_secs_f64(),
+                });
+            } else {
+                observer.event(&Event::StepFailed {
+                    step: index,
+                    op: op_name.clone(),
+                    code: status.code(),
+                });
+            }
+            steps.push(step);
+            if !status.success() {
+                break;
+            }
+        }
+        observer.event(&Event::PlanFinished {
+            success: status.success(),
+            seconds: start_time.elapsed().as_secs_f64(),
+        });
+
+        let end = self.plan.end().map(|end| self.plan.workdir.join(end));
+        let output = match end {
+            Some(_) if !status.success() || self.global_config.dry_run => Output::None,
+            Some(_) if self.driver.states[self.end_state()].is_pseudo() => Output::None,
+            Some(path) if self.plan.stdout => {
+                Output::Bytes(std::fs::read(&path).map_err(Error::at(&path))?)
+            }
+            Some(path) => Output::File(path),
+            None => Output::None,
+        };
+
+        if !self.global_config.keep_build_dir && !stale_dir {
+            std::fs::remove_dir_all(dir).map_err(Error::at(dir))?;
+        }
+
+        Ok(RunResult {
+            status,
+            output,
+            steps,
+            duration: start_time.elapsed(),
+        })
+    }
+
+    fn execute_step(
+        &self,
+        dir: &Utf8Path,
+        index: usize,
+        step: &Step,
+        observer: &mut dyn Observer,
+    ) -> Result<StepResult> {
+        let start_time = Instant::now();
+        let log_before = report::ninja_log_before(dir);
+        let mut child = Command::new(&self.global_config.ninja)
+            .current_dir(dir)
+            .args(self.global_config.ninja_args())
+            .args(&step.outputs)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read both streams on separate threads and collect the lines here, in the order they
+        // arrive, so the observer sees output as it happens.
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let (tx, rx) = mpsc::channel::<(bool, Vec<u8>)>();
+        std::thread::scope(|scope| {
+            let out_pipe = child.stdout.take().expect("stdout is piped");
+            let err_pipe = child.stderr.take().expect("stderr is piped");
+            let out_tx = tx.clone();
+            scope.spawn(move || send_lines(out_pipe, false, out_tx));
+            scope.spawn(move || send_lines(err_pipe, true, tx));
+
+            for (is_err, line) in rx {
+                observer.event(&Event::Output {
+                    step: index,
+                    line: String::from_utf8_lossy(&line).trim_end().to_string(),
+                    stderr: is_err,
+                });
+                if is_err {
+                    stderr.extend(line);
+                } else {
+                    stdout.extend(line);
+                }
+            }
+        });
+        let status = child.wait()?;
+        let duration = start_time.elapsed();
+
+        Ok(StepResult {
+            op: step.op,
+            file: step.output().to_path_buf(),
+            status,
+            stdout,
+            stderr,
+            duration,
+            commands: report::read_ninja_log(dir, &log_before)?,
+        })
+    }
+}
+
+/// Send each line from a pipe over a channel, including its newline.
+fn send_lines(pipe: impl std::io::Read, is_err: bool, tx: mpsc::Sender<(bool, Vec<u8>)>) {
+    let mut reader = std::io::BufReader::new(pipe);
+    loop {
+        let mut line = vec![];
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if tx.send((is_err, line)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::driver::{Driver, DriverBuilder};
+    use crate::testing::{default_config, request, TempDir};
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A stand-in for Ninja that "builds" each target by writing any `.a` input and a line naming
+    /// the target, and logs it like Ninja does. It fails on targets named `fail.*`, printing only to
+    /// stderr so the order of output events is fixed.
+    const NINJA: &str = r#"#!/bin/sh
+for target; do
+    case "$target" in -*) continue ;; esac
+    case "$target" in *fail.*) echo "no good" >&2; exit 3 ;; esac
+    echo "building $target"
+    cat *.a > "$target" 2>/dev/null
+    echo "built $target" >> "$target"
+    printf '0\t5\t0\t%s\t0\n' "$target" >> .ninja_log
+done
+"#;
+
+    fn driver() -> Driver {
+        let mut bld = DriverBuilder::new("exectest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        bld.rule(&[], a, b, "a_to_b");
+        bld.rule(&[], b, c, "b_to_c");
+        bld.build().unwrap()
+    }
+
+    /// Execute a conversion from `in.a` to `output`, both in `tmp`, in the build directory
+    /// `tmp/build`.
+    fn execute(
+        driver: &Driver,
+        tmp: &Utf8Path,
+        output: Option<&str>,
+        observer: &mut dyn Observer,
+    ) -> RunResult {
+        let ninja = tmp.join("ninja");
+        std::fs::write(&ninja, NINJA).unwrap();
+        std::fs::set_permissions(&ninja, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let workdir = tmp.join("build");
+        let mut req = request(driver, "in.a", &["out.c"], &workdir);
+        req.start_file = None;
+        req.targets[0].file = output.map(|file| tmp.join(file));
+        let mut run =
+            Run::with_config(driver, driver.plan(req).unwrap(), default_config()).unwrap();
+        run.global_config.ninja = ninja.to_string();
+        run.execute(&workdir, Some(b"input\n"), observer).unwrap()
+    }
+
+    #[test]
+    fn execution_captures_each_step() {
+        let driver = driver();
+        let tmp = TempDir::new("exec-capture");
+        let res = execute(&driver, &tmp.0, None, &mut ());
+
+        assert!(res.success());
+        let stdout: Vec<_> = res.steps.iter().map(|s| s.stdout.as_slice()).collect();
+        assert_eq!(stdout, [b"building _stdin.b\n", b"building _stdin.c\n"]);
+        assert!(res.steps.iter().all(|s| s.commands.len() == 1));
+        assert!(matches!(
+            res.output,
+            Output::Bytes(bytes) if bytes == b"input\nbuilt _stdin.c\n"
+        ));
+        assert!(!tmp.0.join("build").exists());
+
+        let res = execute(&driver, &tmp.0, Some("out.c"), &mut ());
+        assert!(matches!(res.output, Output::File(path) if path == tmp.0.join("build/../out.c")));
+        assert!(tmp.0.join("out.c").exists());
+    }
+
+    #[test]
+    fn failed_steps_stop_execution() {
+        let driver = driver();
+        let tmp = TempDir::new("exec-fail");
+        let res = execute(&driver, &tmp.0, Some("fail.c"), &mut ());
+
+        assert!(!res.success());
+        assert_eq!(res.steps.len(), 2);
+        let failed = &res.steps[1];
+        assert_eq!(failed.status.code(), Some(3));
+        assert_eq!(failed.stderr, b"no good\n");
+        assert!(failed.commands.is_empty());
+        assert!(matches!(res.output, Output::None));
+    }
+
+    #[test]
+    fn observers_see_each_event() {
+        let driver = driver();
+        let tmp = TempDir::new("exec-events");
+        let mut events: Vec<Event> = vec![];
+        execute(&driver, &tmp.0, Some("fail.c"), &mut events);
+
+        let summary: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                Event::PlanStarted { steps } => format!("plan {}", steps),
+                Event::StepStarted { step, op, file } => format!("start {} {} {}", step, op, file),
+                Event::Output { step, line, stderr } => format!("out {} {} {}", step, line, stderr),
+                Event::StepFinished { step, .. } => format!("done {}", step),
+                Event::StepFailed { step, code, .. } => format!("failed {} {:?}", step, code),
+                Event::PlanFinished { success, .. } => format!("finished {}", success),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "plan 2",
+                "start 0 a_to_b _stdin.b",
+                "out 0 building _stdin.b false",
+                "done 0",
+                "start 1 b_to_c ../fail.c",
+                "out 1 no good true",
+                "failed 1 Some(3)",
+                "finished false",
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_bar_fills_with_finished_steps() {
+        let mut reporter = TermReporter::new(false);
+        reporter.steps = 4;
+        assert_eq!(reporter.bar(0, 0, "op"), "[--------------------] 1/4 op");
+        assert_eq!(reporter.bar(3, 2, "op"), "[###############-----] 3/4 op");
+    }
+}
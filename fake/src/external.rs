@@ -0,0 +1,449 @@
+//! Plugins that run as separate executables, so an installed driver can gain operations without
+//! being rebuilt.
+//!
+//! Every executable in one of the configured `plugin_dirs` is a plugin, named after its file
+//! stem. We talk to it by running it with arguments and reading JSON from its stdout:
+//!
+//! * `describe` prints the states, setups, and operations it provides, as a [`Manifest`].
+//! * `setup NAME` prints the Ninja code for one of its setups, as a list of [`Action`]s.
+//! * `build OP INPUT OUTPUT` prints the Ninja code for one of its operations in the same way.
+//!
+//! A plugin's names get the plugin's name as a namespace, like a static [`Plugin`]. Operations
+//! and setups can refer to the driver's existing states and setups by their plain names.
+//!
+//! Probing emitters (for finding configuration keys, sweeping, and recipe fingerprints) never run
+//! a plugin. They see the operation's `requires` keys and a hash of the `describe` reply instead.
+//!
+//! [`Plugin`]: crate::driver::Plugin
+
+use crate::config::{self, GlobalConfig};
+use crate::driver::DriverBuilder;
+use crate::error::{Error, Result};
+use crate::run::{EmitBuild, EmitResult, EmitSetup, Emitter};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+
+/// What a plugin provides, from its `describe` command.
+#[derive(Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub states: Vec<StateDecl>,
+    #[serde(default)]
+    pub setups: Vec<String>,
+    #[serde(default)]
+    pub ops: Vec<OpDecl>,
+}
+
+#[derive(Deserialize)]
+pub struct StateDecl {
+    pub name: String,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OpDecl {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    #[serde(default)]
+    pub setups: Vec<String>,
+    /// Configuration keys that must be set for the operation to be available.
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+/// One `Emitter` operation requested by a plugin. In JSON, each is an object with a single key
+/// naming the operation, like `{"rule": {"name": "sim", "command": "sim $in > $out"}}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Comment(String),
+    Var {
+        name: String,
+        value: String,
+    },
+    /// Set a variable from the configuration, which is required unless there is a default.
+    ConfigVar {
+        name: String,
+        key: String,
+        default: Option<String>,
+    },
+    Rule {
+        name: String,
+        command: String,
+    },
+    Arg {
+        name: String,
+        value: String,
+    },
+    Depfile {
+        path: String,
+        deps: Option<String>,
+    },
+    Pool {
+        name: String,
+        depth: usize,
+    },
+    Build {
+        targets: Vec<String>,
+        #[serde(default)]
+        implicit_targets: Vec<String>,
+        rule: String,
+        #[serde(default)]
+        deps: Vec<String>,
+        #[serde(default)]
+        implicit_deps: Vec<String>,
+    },
+    AddFile {
+        name: String,
+        contents: String,
+    },
+}
+
+impl Action {
+    fn apply(&self, e: &mut Emitter) -> EmitResult {
+        fn strs(v: &[String]) -> Vec<&str> {
+            v.iter().map(String::as_str).collect()
+        }
+        match self {
+            Action::Comment(text) => e.comment(text)?,
+            Action::Var { name, value } => e.var(name, value)?,
+            Action::ConfigVar {
+                name,
+                key,
+                default: Some(default),
+            } => e.config_var_or(name, key, default)?,
+            Action::ConfigVar {
+                name,
+                key,
+                default: None,
+            } => e.config_var(name, key)?,
+            Action::Rule { name, command } => e.rule(name, command)?,
+            Action::Arg { name, value } => e.arg(name, value)?,
+            Action::Depfile { path, deps } => e.depfile(path, deps.as_deref())?,
+            Action::Pool { name, depth } => e.pool(name, *depth)?,
+            Action::Build {
+                targets,
+                implicit_targets,
+                rule,
+                deps,
+                implicit_deps,
+            } => e.build_cmd_with_outputs(
+                &strs(targets),
+                &strs(implicit_targets),
+                rule,
+                &strs(deps),
+                &strs(implicit_deps),
+            )?,
+            Action::AddFile { name, contents } => e.add_file(name, contents.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+/// Run a plugin and return what it prints.
+fn call_raw(exe: &Utf8Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new(exe)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(Error::at(exe))?;
+    if !output.status.success() {
+        return Err(Error::Exec {
+            cmd: format!("{} {}", exe, args.join(" ")),
+            status: output.status,
+        });
+    }
+    Ok(output.stdout)
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(exe: &Utf8Path, reply: &[u8]) -> Result<T> {
+    serde_json::from_slice(reply).map_err(|err| Error::PluginResponse {
+        path: exe.to_path_buf(),
+        err,
+    })
+}
+
+/// Run a plugin and parse the JSON it prints.
+fn call<T: for<'de> Deserialize<'de>>(exe: &Utf8Path, args: &[&str]) -> Result<T> {
+    parse(exe, &call_raw(exe, args)?)
+}
+
+/// Stand in for a plugin's Ninja code in a probing emitter: look up the keys the plugin declared,
+/// and write a line that changes whenever its `describe` reply does.
+fn emit_probe(e: &mut Emitter, what: &str, version: &str, keys: &[String]) -> EmitResult {
+    for key in keys {
+        e.config_val(key)?;
+    }
+    e.comment(&format!("{} from plugin version {}", what, version))?;
+    Ok(())
+}
+
+fn emit_actions(exe: &Utf8Path, args: &[&str], e: &mut Emitter) -> EmitResult {
+    let actions: Vec<Action> = call(exe, args)?;
+    for action in &actions {
+        action.apply(e)?;
+    }
+    Ok(())
+}
+
+struct ExternalSetup {
+    exe: Utf8PathBuf,
+    name: String,
+    version: String,
+}
+
+impl EmitSetup for ExternalSetup {
+    fn setup(&self, emitter: &mut Emitter) -> EmitResult {
+        if emitter.probe.is_some() {
+            let what = format!("setup {}", self.name);
+            return emit_probe(emitter, &what, &self.version, &[]);
+        }
+        emit_actions(&self.exe, &["setup", &self.name], emitter)
+    }
+}
+
+struct ExternalBuild {
+    exe: Utf8PathBuf,
+    op: String,
+    version: String,
+    requires: Vec<String>,
+}
+
+impl EmitBuild for ExternalBuild {
+    fn build(&self, emitter: &mut Emitter, input: &str, output: &str) -> EmitResult {
+        if emitter.probe.is_some() {
+            let what = format!("op {}", self.op);
+            return emit_probe(emitter, &what, &self.version, &self.requires);
+        }
+        emit_actions(&self.exe, &["build", &self.op, input, output], emitter)
+    }
+}
+
+/// Register everything a plugin describes. This runs inside the plugin's namespace, so local
+/// names are tried (qualified) before the driver's existing ones. `version` identifies the
+/// `describe` reply, for probes.
+fn register(
+    bld: &mut DriverBuilder,
+    exe: &Utf8Path,
+    manifest: Manifest,
+    version: &str,
+) -> Result<()> {
+    let ns = plugin_name(exe);
+    let unknown = |what: &str, name: &str| Error::PluginReference {
+        path: exe.to_path_buf(),
+        what: what.to_string(),
+        name: name.to_string(),
+    };
+    bld.plugin(&ns, |bld| {
+        for state in &manifest.states {
+            let exts: Vec<&str> = state.extensions.iter().map(String::as_str).collect();
+            bld.state(&state.name, &exts);
+        }
+        for name in &manifest.setups {
+            bld.add_setup(
+                name,
+                ExternalSetup {
+                    exe: exe.to_path_buf(),
+                    name: name.clone(),
+                    version: version.to_string(),
+                },
+            );
+        }
+        for op in &manifest.ops {
+            let state = |name: &str| {
+                bld.find_state(&format!("{}.{}", ns, name))
+                    .or_else(|| bld.find_state(name))
+                    .ok_or_else(|| unknown("state", name))
+            };
+            let input = state(&op.input)?;
+            let output = state(&op.output)?;
+            let setups = op
+                .setups
+                .iter()
+                .map(|name| {
+                    bld.find_setup(&format!("{}.{}", ns, name))
+                        .or_else(|| bld.find_setup(name))
+                        .ok_or_else(|| unknown("setup", name))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let op_ref = bld.add_op(
+                &op.name,
+                &setups,
+                input,
+                output,
+                ExternalBuild {
+                    exe: exe.to_path_buf(),
+                    op: op.name.clone(),
+                    version: version.to_string(),
+                    requires: op.requires.clone(),
+                },
+            );
+            let keys: Vec<&str> = op.requires.iter().map(String::as_str).collect();
+            bld.require_config(op_ref, &keys);
+        }
+        Ok(())
+    })
+}
+
+/// The namespace for a plugin: its filename without any extension.
+fn plugin_name(exe: &Utf8Path) -> String {
+    exe.file_stem().unwrap_or(exe.as_str()).to_string()
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.is_file() && meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    meta.is_file()
+}
+
+/// Find the plugin executables in a directory, in order by name. A missing directory has none.
+fn find_plugins(dir: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let entries = match dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(Error::at(dir)(e)),
+    };
+    let mut plugins = vec![];
+    for entry in entries {
+        let entry = entry.map_err(Error::at(dir))?;
+        let meta = entry.path().metadata().map_err(Error::at(entry.path()))?;
+        if is_executable(&meta) {
+            plugins.push(entry.into_path());
+        }
+    }
+    plugins.sort();
+    Ok(plugins)
+}
+
+/// Load the plugin in the executable `exe`.
+pub fn load(bld: &mut DriverBuilder, exe: &Utf8Path) -> Result<()> {
+    let reply = call_raw(exe, &["describe"])?;
+    let version = format!("{:016x}", crate::workdir::fnv1a(&reply));
+    register(bld, exe, parse(exe, &reply)?, &version)
+}
+
+/// Load all the plugins in the directories listed in the `plugin_dirs` option of the driver's
+/// configuration. Relative directories are relative to the config file.
+pub fn load_configured(bld: &mut DriverBuilder) -> Result<()> {
+    let config: GlobalConfig = config::load_config(bld.name())?.extract()?;
+    let config_path = config::config_path(bld.name())?;
+    let base = config_path.parent().unwrap_or(std::path::Path::new("."));
+    for dir in &config.plugin_dirs {
+        let dir = Utf8Path::from_path(base)
+            .ok_or_else(|| Error::BadFilename(base.to_string_lossy().as_ref().into()))?
+            .join(dir);
+        for exe in find_plugins(&dir)? {
+            load(bld, &exe)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::run::{config_keys, Run};
+    use crate::testing::{default_config, request, TempDir};
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A plugin that converts `a` files to its own `b` state, logging each command it runs.
+    const PLUGIN: &str = r#"#!/bin/sh
+echo "$@" >> "$0.log"
+case "$1" in
+describe) echo '{"states": [{"name": "b", "extensions": ["b"]}], "setups": ["tools"],
+  "ops": [{"name": "conv", "input": "a", "output": "b", "setups": ["tools"],
+           "requires": ["demo.exe"]}]}' ;;
+setup) echo '[{"config_var": {"name": "exe", "key": "demo.exe", "default": "conv"}},
+  {"rule": {"name": "conv", "command": "$exe $in > $out"}}]' ;;
+build) echo "[{\"build\": {\"targets\": [\"$4\"], \"rule\": \"conv\", \"deps\": [\"$3\"]}}]" ;;
+*) exit 1 ;;
+esac
+"#;
+
+    /// Write an executable plugin script called `name` into `dir`.
+    fn write_plugin(dir: &Utf8Path, name: &str, script: &str) -> Utf8PathBuf {
+        let exe = dir.join(name);
+        std::fs::write(&exe, script).unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        exe
+    }
+
+    fn load_plugin(dir: &Utf8Path) -> (crate::driver::Driver, Utf8PathBuf) {
+        let exe = write_plugin(dir, "demo", PLUGIN);
+        let mut bld = DriverBuilder::new("plugintest");
+        bld.state("a", &["a"]);
+        load(&mut bld, &exe).unwrap();
+        (bld.build().unwrap(), exe.with_extension("log"))
+    }
+
+    #[test]
+    fn plugin_emits_ninja_code() {
+        let tmp = TempDir::new("plugin-emit");
+        let (driver, _) = load_plugin(&tmp.0);
+        assert!(driver.get_state("demo.b").is_some());
+
+        let (input, output) = (tmp.0.join("in.a"), tmp.0.join("out.b"));
+        let req = request(&driver, input.as_str(), &[output.as_str()], &tmp.0);
+        let run = Run::with_config(&driver, driver.plan(req).unwrap(), default_config()).unwrap();
+        run.emit_to_dir(&tmp.0).unwrap();
+        let ninja = std::fs::read_to_string(tmp.0.join("build.ninja")).unwrap();
+        assert!(ninja.contains("exe = conv\n"));
+        assert!(ninja.contains("rule conv\n  command = $exe $in > $out\n"));
+        assert!(ninja.contains("build out.b: conv in.a\n"));
+    }
+
+    #[test]
+    fn probes_do_not_run_plugins() {
+        let tmp = TempDir::new("plugin-probe");
+        let (driver, log) = load_plugin(&tmp.0);
+        assert!(config_keys(&driver).contains("demo.exe"));
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "describe\n");
+    }
+
+    #[test]
+    fn bad_plugins_are_errors() {
+        let tmp = TempDir::new("plugin-errors");
+        let mut bld = DriverBuilder::new("plugintest");
+        bld.state("a", &["a"]);
+
+        let garbled = write_plugin(&tmp.0, "garbled", "#!/bin/sh\necho not json\n");
+        assert!(matches!(
+            load(&mut bld, &garbled),
+            Err(Error::PluginResponse { path, .. }) if path == garbled
+        ));
+
+        let failing = write_plugin(&tmp.0, "failing", "#!/bin/sh\nexit 1\n");
+        assert!(matches!(load(&mut bld, &failing), Err(Error::Exec { .. })));
+
+        let script = r#"#!/bin/sh
+echo '{"ops": [{"name": "x", "input": "a", "output": "nowhere"}]}'
+"#;
+        let dangling = write_plugin(&tmp.0, "dangling", script);
+        let err = load(&mut bld, &dangling).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("plugin {} refers to unknown state nowhere", dangling)
+        );
+    }
+
+    #[test]
+    fn configured_dirs_hold_executables() {
+        let tmp = TempDir::new("plugin-dirs");
+        write_plugin(&tmp.0, "b", "");
+        write_plugin(&tmp.0, "a.py", "");
+        std::fs::write(tmp.0.join("notes.txt"), "").unwrap();
+        let found = find_plugins(&tmp.0).unwrap();
+        assert_eq!(found, [tmp.0.join("a.py"), tmp.0.join("b")]);
+        assert_eq!(plugin_name(&found[0]), "a");
+        assert!(find_plugins(&tmp.0.join("missing")).unwrap().is_empty());
+    }
+}
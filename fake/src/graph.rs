@@ -0,0 +1,202 @@
+use crate::driver::{Driver, OpRef, SetupRef, StateRef};
+use std::io::Write;
+
+/// Options for drawing the whole graph of a Driver.
+#[derive(Default)]
+pub struct GraphOptions {
+    /// Emphasize the states and operations that are reachable from this state.
+    pub highlight: Option<StateRef>,
+
+    /// Group operations into clusters according to the setup they use. Operations that use
+    /// several setups go with the last one, which is usually the most specific.
+    pub cluster: bool,
+}
+
+/// A view of a Driver's states, operations, and setups for drawing.
+pub struct Graph<'a> {
+    driver: &'a Driver,
+    opts: GraphOptions,
+    reachable: Vec<StateRef>,
+}
+
+impl<'a> Graph<'a> {
+    pub fn new(driver: &'a Driver, opts: GraphOptions) -> Self {
+        let reachable = match opts.highlight {
+            Some(start) => driver.reachable_states(start),
+            None => vec![],
+        };
+        Self {
+            driver,
+            opts,
+            reachable,
+        }
+    }
+
+    fn state_highlighted(&self, state: StateRef) -> bool {
+        self.reachable.contains(&state)
+    }
+
+    fn op_highlighted(&self, op: OpRef) -> bool {
+        self.reachable.contains(&self.driver.ops[op].input)
+    }
+
+    /// The setup whose cluster contains an operation, if we're clustering.
+    fn cluster(&self, op: OpRef) -> Option<SetupRef> {
+        if self.opts.cluster {
+            self.driver.ops[op].setups.last().copied()
+        } else {
+            None
+        }
+    }
+
+    /// Get the operations that go in a given cluster, or outside of any cluster for `None`.
+    fn cluster_ops(&self, cluster: Option<SetupRef>) -> impl Iterator<Item = OpRef> + '_ {
+        self.driver
+            .ops
+            .keys()
+            .filter(move |op| self.cluster(*op) == cluster)
+    }
+
+    /// Write a GraphViz representation of the graph.
+    pub fn write_dot(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "digraph \"{}\" {{", self.driver.name)?;
+        writeln!(out, "  rankdir=LR;")?;
+        writeln!(out, "  node[shape=box];")?;
+
+        // States are boxes.
+        for (state_ref, state) in self.driver.states.iter() {
+            write!(out, "  {} [label=\"{}\"", state_ref, state.name)?;
+            if state.is_pseudo() {
+                write!(out, " style=dashed")?;
+            }
+            if self.state_highlighted(state_ref) {
+                write!(out, " penwidth=3")?;
+            }
+            writeln!(out, "];")?;
+        }
+
+        // Operations are ellipses, possibly grouped into a cluster for each setup.
+        let write_op = |out: &mut dyn Write, indent: &str, op_ref: OpRef| {
+            let op = &self.driver.ops[op_ref];
+            write!(
+                out,
+                "{}{} [label=\"{}\" shape=ellipse",
+                indent, op_ref, op.name
+            )?;
+            if self.op_highlighted(op_ref) {
+                write!(out, " penwidth=3")?;
+            }
+            writeln!(out, "];")
+        };
+        for op in self.cluster_ops(None) {
+            write_op(out, "  ", op)?;
+        }
+        if self.opts.cluster {
+            for (setup_ref, setup) in self.driver.setups.iter() {
+                writeln!(out, "  subgraph cluster_{} {{", setup_ref)?;
+                writeln!(out, "    label=\"{}\";", setup.name)?;
+                for op in self.cluster_ops(Some(setup_ref)) {
+                    write_op(out, "    ", op)?;
+                }
+                writeln!(out, "  }}")?;
+            }
+        } else {
+            // Without clusters, setups are separate nodes linked to the operations that use them.
+            for (setup_ref, setup) in self.driver.setups.iter() {
+                writeln!(
+                    out,
+                    "  {} [label=\"{}\" shape=note];",
+                    setup_ref, setup.name
+                )?;
+            }
+        }
+
+        // Edges connect each operation to its input and output.
+        for (op_ref, op) in self.driver.ops.iter() {
+            let style = if self.op_highlighted(op_ref) {
+                " [penwidth=3]"
+            } else {
+                ""
+            };
+            writeln!(out, "  {} -> {}{};", op.input, op_ref, style)?;
+            writeln!(out, "  {} -> {}{};", op_ref, op.output, style)?;
+            if !self.opts.cluster {
+                for setup in &op.setups {
+                    writeln!(out, "  {} -> {} [style=dashed];", setup, op_ref)?;
+                }
+            }
+        }
+
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+
+    /// Write a Mermaid flowchart of the graph.
+    pub fn write_mermaid(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "flowchart LR")?;
+
+        // States are rectangles; pseudo-states are hexagons.
+        for (state_ref, state) in self.driver.states.iter() {
+            if state.is_pseudo() {
+                writeln!(out, "  {}{{{{\"{}\"}}}}", state_ref, state.name)?;
+            } else {
+                writeln!(out, "  {}[\"{}\"]", state_ref, state.name)?;
+            }
+        }
+
+        // Operations are rounded, possibly grouped into a subgraph for each setup.
+        let write_op = |out: &mut dyn Write, indent: &str, op_ref: OpRef| {
+            writeln!(
+                out,
+                "{}{}([\"{}\"])",
+                indent, op_ref, self.driver.ops[op_ref].name
+            )
+        };
+        for op in self.cluster_ops(None) {
+            write_op(out, "  ", op)?;
+        }
+        if self.opts.cluster {
+            for (setup_ref, setup) in self.driver.setups.iter() {
+                writeln!(out, "  subgraph {}[\"{}\"]", setup_ref, setup.name)?;
+                for op in self.cluster_ops(Some(setup_ref)) {
+                    write_op(out, "    ", op)?;
+                }
+                writeln!(out, "  end")?;
+            }
+        } else {
+            for (setup_ref, setup) in self.driver.setups.iter() {
+                writeln!(out, "  {}[/\"{}\"/]", setup_ref, setup.name)?;
+            }
+        }
+
+        // Edges.
+        for (op_ref, op) in self.driver.ops.iter() {
+            writeln!(out, "  {} --> {} --> {}", op.input, op_ref, op.output)?;
+            if !self.opts.cluster {
+                for setup in &op.setups {
+                    writeln!(out, "  {} -.-> {}", setup, op_ref)?;
+                }
+            }
+        }
+
+        // Highlight reachable nodes with a class.
+        if self.opts.highlight.is_some() {
+            let nodes: Vec<String> = self
+                .reachable
+                .iter()
+                .map(|s| s.to_string())
+                .chain(
+                    self.driver
+                        .ops
+                        .keys()
+                        .filter(|op| self.op_highlighted(*op))
+                        .map(|op| op.to_string()),
+                )
+                .collect();
+            writeln!(out, "  classDef reachable stroke-width:3px")?;
+            writeln!(out, "  class {} reachable", nodes.join(","))?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,16 @@
 pub mod cli;
+pub mod completions;
 pub mod config;
 pub mod driver;
+pub mod error;
+pub mod exec;
+pub mod external;
+pub mod graph;
+pub mod recipe;
+pub mod report;
 pub mod run;
+pub mod sweep;
+pub mod testing;
+mod workdir;
 
 pub use driver::{Driver, DriverBuilder};
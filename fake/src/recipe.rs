@@ -0,0 +1,439 @@
+//! Build recipes, which record a plan along with everything needed to execute it again later,
+//! possibly somewhere else. A recipe holds the plan, the configuration values set on the command
+//! line, and the name and version of the driver that made it. Everything specific to the machine
+//! is left out: the input and output files are relative to the directory the recipe was recorded
+//! in, the build directory is chosen again on replay, and settings like tool paths come from the
+//! config file where the recipe is replayed.
+//!
+//! Plans refer to operations and states by index, which can change between versions of a driver.
+//! So a recipe also lists the driver's operations and states by name, and replaying it looks them
+//! up again. Each operation also has a fingerprint of the Ninja code it emits, so we can warn
+//! when an operation the plan uses has changed since the recipe was recorded.
+
+use crate::driver::{relative_path, Driver, OpRef, Plan, StateRef};
+use crate::error::{Error, Result};
+use crate::run::{Capture, Emitter, Run};
+use crate::workdir;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cranelift_entity::EntityRef;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+#[derive(Serialize, Deserialize)]
+pub struct Recipe {
+    /// The name of the driver that made the plan.
+    pub driver: String,
+
+    /// The driver's version, if it has one.
+    pub version: Option<String>,
+
+    /// The driver's operations, in order, so the plan's references to them can be resolved.
+    pub ops: Vec<OpRecord>,
+
+    /// The names of the driver's states, in order.
+    pub states: Vec<String>,
+
+    /// The plan, without its build directory. Its files are relative to the build directory,
+    /// except for the ones in `external`.
+    pub plan: Plan,
+
+    /// The plan's files outside the build directory, like the input and outputs, relative to the
+    /// directory the recipe was recorded in.
+    pub external: Vec<Utf8PathBuf>,
+
+    /// The configuration values set on the command line.
+    pub overrides: Vec<(String, String)>,
+}
+
+/// An operation as it was when a recipe was recorded.
+#[derive(Serialize, Deserialize)]
+pub struct OpRecord {
+    pub name: String,
+
+    /// A hash of the Ninja code that the operation and its setups emit, for the operations that
+    /// the plan uses.
+    pub fingerprint: Option<String>,
+}
+
+/// Fingerprint an operation by emitting it, along with its setups, for placeholder files. Like
+/// `run::config_keys`, this uses a probe, so the Ninja code doesn't depend on the configuration.
+fn fingerprint(driver: &Driver, op: OpRef) -> String {
+    let op = &driver.ops[op];
+    let capture = Capture::default();
+    let mut emitter = Emitter {
+        probe: Some(Default::default()),
+        ..Emitter::new(capture.clone(), Default::default(), ".".into())
+    };
+
+    // Errors just make for a different fingerprint, which is all we need.
+    let _ = writeln!(
+        emitter.out,
+        "{}: {} -> {}",
+        op.name, driver.states[op.input].name, driver.states[op.output].name
+    );
+    for &setup in &op.setups {
+        let _ = driver.setups[setup].emit.setup(&mut emitter);
+    }
+    let _ = op.emit.build(&mut emitter, "input", "output");
+
+    drop(emitter);
+    let code = capture.0.borrow();
+    format!("{:016x}", workdir::fnv1a(&code))
+}
+
+/// Replace every file in a plan with `f(file)`.
+fn map_files(plan: &mut Plan, mut f: impl FnMut(&Utf8Path) -> Result<Utf8PathBuf>) -> Result<()> {
+    plan.start = f(&plan.start)?;
+    for target in &mut plan.targets {
+        *target = f(target)?;
+    }
+    for step in &mut plan.steps {
+        for file in step.inputs.iter_mut().chain(&mut step.outputs) {
+            *file = f(file)?;
+        }
+    }
+    Ok(())
+}
+
+impl Recipe {
+    /// Record the plan and configuration for a run.
+    pub fn new(run: &Run) -> Result<Self> {
+        let driver = run.driver;
+        let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)
+            .map_err(|e| std::io::Error::other(e.into_io_error()))?;
+
+        // Files in the build directory stay relative to it. The rest are made relative to the
+        // current directory. When the build happens in the current directory, every file stays
+        // there on replay, as it did here.
+        let mut plan = run.plan.clone();
+        let workdir = std::mem::take(&mut plan.workdir);
+        let in_cwd = workdir.components().all(|c| c == Utf8Component::CurDir) || workdir == cwd;
+        let mut external = vec![];
+        map_files(&mut plan, |file| {
+            let inside = matches!(file.components().next(), Some(Utf8Component::Normal(_)));
+            if inside && !in_cwd {
+                return Ok(file.to_path_buf());
+            }
+            let full = workdir::join(&workdir, file);
+            let file = if full.is_absolute() {
+                relative_path(&full, &cwd)?
+            } else {
+                full
+            };
+            if !external.contains(&file) {
+                external.push(file.clone());
+            }
+            Ok(file)
+        })?;
+
+        Ok(Self {
+            driver: driver.name.clone(),
+            version: driver.version.clone(),
+            ops: driver
+                .ops
+                .iter()
+                .map(|(op_ref, op)| OpRecord {
+                    name: op.name.clone(),
+                    fingerprint: run
+                        .plan
+                        .steps
+                        .iter()
+                        .any(|step| step.op == op_ref)
+                        .then(|| fingerprint(driver, op_ref)),
+                })
+                .collect(),
+            states: driver.states.values().map(|s| s.name.clone()).collect(),
+            plan,
+            external,
+            overrides: run.overrides.clone(),
+        })
+    }
+
+    /// The plan's input file, relative to the directory the recipe was recorded in, unless it
+    /// reads stdin.
+    pub fn input(&self) -> Option<&Utf8Path> {
+        (!self.plan.stdin).then_some(self.plan.start.as_path())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+
+    /// Read a recipe from a file.
+    pub fn read(path: &Utf8Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(Error::at(path))?;
+        serde_json::from_slice(&data).map_err(|err| Error::BadRecipe {
+            path: path.to_path_buf(),
+            err,
+        })
+    }
+
+    /// Set up a run that executes the recipe's plan with `driver` in the build directory
+    /// `workdir`. The recorded configuration values take precedence over `config_data`. Also
+    /// return warnings about differences between the driver and the one that recorded the recipe:
+    /// a different version, or a change to any operation that the plan uses.
+    pub fn into_run<'a>(
+        self,
+        driver: &'a Driver,
+        config_data: figment::Figment,
+        workdir: &Utf8Path,
+    ) -> Result<(Run<'a>, Vec<String>)> {
+        if self.driver != driver.name {
+            return Err(Error::RecipeMismatch(format!(
+                "the recipe is for {}, not {}",
+                self.driver, driver.name
+            )));
+        }
+        let mut warnings = vec![];
+        if self.version != driver.version {
+            let show = |v: &Option<String>| v.clone().unwrap_or("an unknown version".into());
+            warnings.push(format!(
+                "the recipe was recorded with {} {}, but this is {}",
+                driver.name,
+                show(&self.version),
+                show(&driver.version)
+            ));
+        }
+
+        // Find the driver's current operations and states with the recorded names.
+        let find_op = |op: OpRef| {
+            let name = self.ops.get(op.index()).map(|r| r.name.as_str());
+            name.and_then(|name| driver.get_op(name)).ok_or_else(|| {
+                Error::RecipeMismatch(format!(
+                    "the recipe uses operation {}, which {} does not have",
+                    name.unwrap_or("(unknown)"),
+                    driver.name
+                ))
+            })
+        };
+        let find_state = |state: StateRef| {
+            let name = self.states.get(state.index()).map(|s| s.as_str());
+            name.and_then(|name| driver.get_state(name)).ok_or_else(|| {
+                Error::RecipeMismatch(format!(
+                    "the recipe uses state {}, which {} does not have",
+                    name.unwrap_or("(unknown)"),
+                    driver.name
+                ))
+            })
+        };
+
+        let mut plan = self.plan;
+        let mut checked = vec![];
+        for step in &mut plan.steps {
+            let recorded = step.op;
+            step.op = find_op(recorded)?;
+            if !checked.contains(&step.op) {
+                checked.push(step.op);
+                let saved = self.ops[recorded.index()].fingerprint.as_deref();
+                if saved != Some(&fingerprint(driver, step.op)) {
+                    warnings.push(format!(
+                        "operation {} has changed since the recipe was recorded",
+                        driver.ops[step.op].name
+                    ));
+                }
+            }
+        }
+        plan.start_state = find_state(plan.start_state)?;
+
+        // These only describe how the plan was made, so we can do without anything that's gone.
+        plan.through = plan
+            .through
+            .into_iter()
+            .filter_map(|op| find_op(op).ok())
+            .collect();
+        plan.avoid_ops = plan
+            .avoid_ops
+            .into_iter()
+            .filter_map(|op| find_op(op).ok())
+            .collect();
+        plan.avoid_states = plan
+            .avoid_states
+            .into_iter()
+            .filter_map(|state| find_state(state).ok())
+            .collect();
+        plan.skipped = plan
+            .skipped
+            .into_iter()
+            .filter_map(|(op, reason)| Some((find_op(op).ok()?, reason)))
+            .collect();
+
+        // Put the build in its new directory.
+        map_files(&mut plan, |file| {
+            if self.external.iter().any(|f| f == file) {
+                relative_path(file, workdir)
+            } else {
+                Ok(file.to_path_buf())
+            }
+        })?;
+        plan.workdir = workdir.to_path_buf();
+
+        let mut run = Run::with_config(driver, plan, config_data)?;
+        for (key, value) in &self.overrides {
+            run.set_config(key, value);
+        }
+        Ok((run, warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::DriverBuilder;
+    use crate::run::EmitBuildFn;
+    use crate::testing::{default_config, request, TempDir};
+
+    /// A driver that converts `a` to `b` to `c`, where `b-to-c` emits `build_c`.
+    fn driver(build_c: EmitBuildFn) -> Driver {
+        let mut bld = DriverBuilder::new("recipetest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let setup = bld.setup("tools", |e| {
+            e.rule("copy", "cp $in $out")?;
+            Ok(())
+        });
+        bld.rule(&[setup], a, b, "copy");
+        bld.op("b-to-c", &[setup], b, c, build_c);
+        bld.build().unwrap()
+    }
+
+    fn copy_c(e: &mut Emitter, input: &str, output: &str) -> crate::run::EmitResult {
+        e.build_cmd(&[output], "copy", &[input], &[])?;
+        Ok(())
+    }
+
+    /// Record a recipe for converting `in.a` to `out.c` in `workdir`, and round-trip it through
+    /// JSON.
+    fn record(driver: &Driver, workdir: &Utf8Path, overrides: &[(&str, &str)]) -> Recipe {
+        let req = request(driver, "in.a", &["out.c"], workdir);
+        let mut run =
+            Run::with_config(driver, driver.plan(req).unwrap(), default_config()).unwrap();
+        for (key, value) in overrides {
+            run.set_config(key, value);
+        }
+        let mut json = vec![];
+        Recipe::new(&run).unwrap().write_json(&mut json).unwrap();
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    #[test]
+    fn changed_op_warns_on_replay() {
+        let original = driver(copy_c);
+        let recipe = record(&original, ".".into(), &[]);
+
+        // Replaying with the same driver is quiet.
+        let (_, warnings) = recipe
+            .into_run(&original, default_config(), ".".into())
+            .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+
+        // An operation that now emits different code gets a warning.
+        let changed = driver(|e, input, output| {
+            e.build_cmd(&[output], "copy", &[input], &[])?;
+            e.arg("flags", "-v")?;
+            Ok(())
+        });
+        let recipe = record(&original, ".".into(), &[]);
+        let (run, warnings) = recipe
+            .into_run(&changed, default_config(), ".".into())
+            .unwrap();
+        assert_eq!(
+            warnings,
+            ["operation b-to-c has changed since the recipe was recorded"]
+        );
+        assert_eq!(run.plan.steps.len(), 2);
+    }
+
+    #[test]
+    fn recipes_are_portable() {
+        let driver = driver(copy_c);
+        let tmp = TempDir::new("recipe");
+        let recipe = record(&driver, &tmp.0, &[("tools.level", "2")]);
+
+        // The machine's build directory is left out, and the input and output are relative to the
+        // current directory.
+        assert_eq!(recipe.plan.workdir, "");
+        assert_eq!(recipe.external, ["in.a", "out.c"]);
+        assert_eq!(recipe.input(), Some("in.a".into()));
+        assert_eq!(recipe.plan.steps[0].outputs, ["in.b"]);
+        assert_eq!(recipe.overrides, [("tools.level".into(), "2".into())]);
+
+        // Replaying puts the intermediate files in the new build directory, and finds the input and
+        // output from there. Only the recorded values override the local configuration.
+        let config = config_with(&[("ninja", "local-ninja")]);
+        let (run, _) = recipe.into_run(&driver, config, ".build".into()).unwrap();
+        assert_eq!(run.plan.workdir, ".build");
+        assert_eq!(run.plan.start, "../in.a");
+        assert_eq!(run.plan.targets, ["../out.c"]);
+        assert_eq!(run.plan.steps[0].outputs, ["in.b"]);
+        assert_eq!(run.plan.steps[1].inputs, ["in.b"]);
+        assert_eq!(run.global_config.ninja, "local-ninja");
+        assert_eq!(
+            run.config_data
+                .extract_inner::<String>("tools.level")
+                .unwrap(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn replay_finds_ops_and_states_by_name() {
+        let original = driver(copy_c);
+        let recipe = record(&original, ".".into(), &[]);
+
+        // The same operations and states, registered in a different order after new ones.
+        let mut bld = DriverBuilder::new("recipetest");
+        let z = bld.state("z", &["z"]);
+        let c = bld.state("c", &["c"]);
+        let b = bld.state("b", &["b"]);
+        let a = bld.state("a", &["a"]);
+        let setup = bld.setup("tools", |e| {
+            e.rule("copy", "cp $in $out")?;
+            Ok(())
+        });
+        bld.rule(&[], a, z, "a-to-z");
+        bld.op("b-to-c", &[setup], b, c, copy_c);
+        bld.rule(&[setup], a, b, "copy");
+        let reordered = bld.build().unwrap();
+
+        let (run, warnings) = recipe
+            .into_run(&reordered, default_config(), ".".into())
+            .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let ops: Vec<_> = run
+            .plan
+            .steps
+            .iter()
+            .map(|s| reordered.ops[s.op].name.as_str())
+            .collect();
+        assert_eq!(ops, ["copy", "b-to-c"]);
+        assert_eq!(run.plan.start_state, a);
+
+        // A driver without one of the operations can't replay the recipe.
+        let mut bld = DriverBuilder::new("recipetest");
+        let a = bld.state("a", &["a"]);
+        let c = bld.state("c", &["c"]);
+        bld.rule(&[], a, c, "copy");
+        let missing = bld.build().unwrap();
+        let recipe = record(&original, ".".into(), &[]);
+        let Err(Error::RecipeMismatch(msg)) =
+            recipe.into_run(&missing, default_config(), ".".into())
+        else {
+            panic!("expected a mismatch");
+        };
+        assert_eq!(
+            msg,
+            "the recipe uses operation b-to-c, which recipetest does not have"
+        );
+    }
+
+    /// The default configuration with some values set, as if from a config file.
+    fn config_with(values: &[(&str, &str)]) -> figment::Figment {
+        values
+            .iter()
+            .fold(default_config(), |config, (key, value)| {
+                crate::config::set_value(config, key, value)
+            })
+    }
+}
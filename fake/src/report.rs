@@ -0,0 +1,204 @@
+use crate::error::{Error, Result};
+use crate::exec::RunResult;
+use crate::run::Run;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::time::Duration;
+
+/// A command that Ninja ran, as recorded in its `.ninja_log`.
+#[derive(Debug)]
+pub struct LogEntry {
+    /// The output file that the command built.
+    pub output: Utf8PathBuf,
+
+    /// The time the command ran for.
+    pub duration: Duration,
+}
+
+/// The lines of Ninja's log in `dir`, or nothing if there is no log.
+fn ninja_log_lines(dir: &Utf8Path) -> Result<Vec<String>> {
+    let path = dir.join(".ninja_log");
+    match std::fs::read(&path) {
+        Ok(data) => Ok(String::from_utf8_lossy(&data)
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(Error::at(&path)(e)),
+    }
+}
+
+/// The entries already in Ninja's log in `dir`, so we can later tell which ones a run added.
+pub(crate) fn ninja_log_before(dir: &Utf8Path) -> HashSet<String> {
+    ninja_log_lines(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+}
+
+/// Read the entries in Ninja's log in `dir` that are not in `before`, keeping only the last entry
+/// for each output. A missing log has no entries. Ninja sometimes compacts its log by rewriting
+/// it, so we compare whole entries rather than remembering where the log ended.
+pub(crate) fn read_ninja_log(dir: &Utf8Path, before: &HashSet<String>) -> Result<Vec<LogEntry>> {
+    // Each line is `start \t end \t mtime \t output \t hash`, with times in milliseconds.
+    let entries: Vec<LogEntry> = ninja_log_lines(dir)?
+        .iter()
+        .filter(|line| !before.contains(*line))
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let start: u64 = fields.next()?.parse().ok()?;
+            let end: u64 = fields.next()?.parse().ok()?;
+            let output = fields.nth(1)?;
+            Some(LogEntry {
+                output: output.into(),
+                duration: Duration::from_millis(end.saturating_sub(start)),
+            })
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    let mut entries: Vec<LogEntry> = entries
+        .into_iter()
+        .rev()
+        .filter(|entry| seen.insert(entry.output.clone()))
+        .collect();
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Timing for one step in a plan.
+#[derive(Serialize)]
+pub struct StepReport {
+    pub op: String,
+    pub file: Utf8PathBuf,
+
+    /// Wall-clock time for the step.
+    pub seconds: f64,
+
+    /// Time spent in the commands that Ninja ran, according to its log.
+    pub command_seconds: f64,
+
+    /// Was the step's output already up to date, so no commands ran? This is never true for a dry
+    /// run, where no commands run at all.
+    pub cached: bool,
+
+    /// The build tool's exit code, if it exited normally.
+    pub exit_code: Option<i32>,
+}
+
+/// A summary of where the time went in an execution.
+#[derive(Serialize)]
+pub struct Report {
+    pub success: bool,
+
+    /// Wall-clock time for the whole execution, including emitting the build file.
+    pub seconds: f64,
+
+    /// The steps that ran, in order. Steps after a failed one are absent.
+    pub steps: Vec<StepReport>,
+}
+
+impl Report {
+    pub fn new(run: &Run, res: &RunResult) -> Self {
+        let steps = res
+            .steps
+            .iter()
+            .map(|step| StepReport {
+                op: run.driver.ops[step.op].name.clone(),
+                file: step.file.clone(),
+                seconds: step.duration.as_secs_f64(),
+                command_seconds: step.commands.iter().map(|c| c.duration.as_secs_f64()).sum(),
+                cached: !run.global_config.dry_run
+                    && step.status.success()
+                    && step.commands.is_empty(),
+                exit_code: step.status.code(),
+            })
+            .collect();
+        Self {
+            success: res.success(),
+            seconds: res.duration.as_secs_f64(),
+            steps,
+        }
+    }
+
+    /// Print a table with the time for each step and its share of the total.
+    pub fn write_table(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let width = self
+            .steps
+            .iter()
+            .map(|s| s.op.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or(0);
+        writeln!(
+            out,
+            "{:<width$}  {:>9}  {:>5}  status",
+            "op", "seconds", "%"
+        )?;
+        for step in &self.steps {
+            let status = match step.exit_code {
+                _ if step.cached => "cached".to_string(),
+                Some(0) => "ok".to_string(),
+                Some(code) => format!("failed ({})", code),
+                None => "killed".to_string(),
+            };
+            let share = if self.seconds > 0.0 {
+                100.0 * step.seconds / self.seconds
+            } else {
+                0.0
+            };
+            writeln!(
+                out,
+                "{:<width$}  {:>9.3}  {:>5.1}  {}",
+                step.op, step.seconds, share, status
+            )?;
+        }
+        writeln!(out, "{:<width$}  {:>9.3}", "total", self.seconds)?;
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn write_log(dir: &Utf8Path, entries: &[&str]) {
+        let log = format!("# ninja log v5\n{}", entries.join(""));
+        std::fs::write(dir.join(".ninja_log"), log).unwrap();
+    }
+
+    fn outputs(entries: &[LogEntry]) -> Vec<(&str, u64)> {
+        entries
+            .iter()
+            .map(|e| (e.output.as_str(), e.duration.as_millis() as u64))
+            .collect()
+    }
+
+    #[test]
+    fn ninja_log_has_only_new_entries() {
+        let tmp = TempDir::new("ninja-log");
+        assert!(read_ninja_log(&tmp.0, &ninja_log_before(&tmp.0))
+            .unwrap()
+            .is_empty());
+
+        let old = "0\t10\t1\ta.sv\t1\n";
+        write_log(&tmp.0, &[old]);
+        let before = ninja_log_before(&tmp.0);
+
+        // The old entry survives a compaction, and the run logs `b.dat` twice.
+        write_log(
+            &tmp.0,
+            &[old, "0\t5\t2\tb.dat\t2\n", "5\t12\t3\tb.dat\t2\n"],
+        );
+        let entries = read_ninja_log(&tmp.0, &before).unwrap();
+        assert_eq!(outputs(&entries), [("b.dat", 7)]);
+    }
+}
@@ -1,39 +1,36 @@
 use crate::config;
-use crate::driver::{relative_path, Driver, OpRef, Plan, SetupRef, StateRef};
+use crate::driver::{relative_path, Driver, OpRef, Plan, SetupRef, StateRef, Step};
+use crate::error::{Error, Result};
+use crate::report;
+use crate::workdir;
 use camino::{Utf8Path, Utf8PathBuf};
-use std::collections::{HashMap, HashSet};
+use notify::Watcher;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::Write;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::rc::Rc;
 
-/// An error that arises while emitting the Ninja file.
-#[derive(Debug)]
-pub enum EmitError {
-    Io(std::io::Error),
-    MissingConfig(String),
-}
-
-impl From<std::io::Error> for EmitError {
-    fn from(e: std::io::Error) -> Self {
-        Self::Io(e)
-    }
-}
-
-impl std::fmt::Display for EmitError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            EmitError::Io(e) => write!(f, "{}", e),
-            EmitError::MissingConfig(s) => write!(f, "missing required config key: {}", s),
-        }
-    }
-}
-
-impl std::error::Error for EmitError {}
-
-pub type EmitResult = std::result::Result<(), EmitError>;
+pub type EmitResult = Result<()>;
 
 /// Code to emit a Ninja `build` command.
 pub trait EmitBuild {
     fn build(&self, emitter: &mut Emitter, input: &str, output: &str) -> EmitResult;
+
+    /// Emit the build commands for a plan step, which may have extra input and output files
+    /// beyond the main ones. Operations that can use them override this; by default, the main
+    /// input and output are passed to `build`, and extra files are an error.
+    fn build_step(&self, emitter: &mut Emitter, inputs: &[&str], outputs: &[&str]) -> EmitResult {
+        match (inputs, outputs) {
+            ([input], [output]) => self.build(emitter, input, output),
+            _ => {
+                let extra = inputs.iter().skip(1).chain(outputs.iter().skip(1));
+                Err(Error::ExtraFiles(extra.map(|f| f.to_string()).collect()))
+            }
+        }
+    }
 }
 
 pub type EmitBuildFn = fn(&mut Emitter, &str, &str) -> EmitResult;
@@ -70,24 +67,113 @@ impl EmitSetup for EmitSetupFn {
     }
 }
 
+/// The JSON representation of a plan, from `Run::show_json`.
+#[derive(Serialize)]
+struct PlanJson<'a> {
+    input: FileJson<'a>,
+    steps: Vec<StepJson<'a>>,
+    targets: Vec<String>,
+    setups: Vec<&'a str>,
+    workdir: String,
+    stdin: bool,
+    stdout: bool,
+    through: Vec<&'a str>,
+    avoid: Vec<&'a str>,
+    skipped: Vec<SkippedJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct SkippedJson<'a> {
+    op: &'a str,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct StepJson<'a> {
+    op: &'a str,
+    inputs: Vec<FileJson<'a>>,
+    outputs: Vec<FileJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct FileJson<'a> {
+    file: String,
+    state: Option<&'a str>,
+}
+
+/// A change to the global options, like the ones command-line flags make.
+type GlobalOverride = Box<dyn Fn(&mut config::GlobalConfig)>;
+
 pub struct Run<'a> {
     pub driver: &'a Driver,
     pub plan: Plan,
     pub config_data: figment::Figment,
     pub global_config: config::GlobalConfig,
+
+    /// Configuration values set on top of the config file, which survive a reload.
+    pub(crate) overrides: Vec<(String, String)>,
+
+    /// Changes to the global options, from `override_global`, which also survive a reload.
+    global_overrides: Vec<GlobalOverride>,
+
+    /// The contents of stdin, if they have already been read.
+    stdin_data: Option<Vec<u8>>,
 }
 
 impl<'a> Run<'a> {
-    pub fn new(driver: &'a Driver, plan: Plan) -> Self {
-        let config_data = config::load_config(&driver.name);
-        let global_config: config::GlobalConfig =
-            config_data.extract().expect("failed to load config");
-        Self {
+    pub fn new(driver: &'a Driver, plan: Plan) -> Result<Self> {
+        Self::with_config(driver, plan, config::load_config(&driver.name)?)
+    }
+
+    /// Set up a run with the given configuration instead of the config file.
+    pub fn with_config(
+        driver: &'a Driver,
+        plan: Plan,
+        config_data: figment::Figment,
+    ) -> Result<Self> {
+        let global_config: config::GlobalConfig = config_data.extract()?;
+        Ok(Self {
             driver,
             plan,
             config_data,
             global_config,
+            overrides: vec![],
+            global_overrides: vec![],
+            stdin_data: None,
+        })
+    }
+
+    /// Override a configuration value, taking precedence over the config file.
+    pub fn set_config(&mut self, key: &str, value: &str) {
+        self.overrides.push((key.to_string(), value.to_string()));
+        self.config_data = config::set_value(std::mem::take(&mut self.config_data), key, value);
+    }
+
+    /// Change the global options, like a command-line flag would. The change is applied again
+    /// whenever the config file is reloaded.
+    pub fn override_global(&mut self, change: impl Fn(&mut config::GlobalConfig) + 'static) {
+        change(&mut self.global_config);
+        self.global_overrides.push(Box::new(change));
+    }
+
+    /// Provide the plan's input, for when the caller has already consumed stdin (for example, to
+    /// sniff its contents).
+    pub fn set_stdin(&mut self, data: Vec<u8>) {
+        self.stdin_data = Some(data);
+    }
+
+    /// Re-read the config file, keeping any values set with `set_config` and any changes from
+    /// `override_global`.
+    fn reload_config(&mut self) -> Result<()> {
+        self.config_data = config::load_config(&self.driver.name)?;
+        for (key, value) in std::mem::take(&mut self.overrides) {
+            self.set_config(&key, &value);
+        }
+        self.global_config = self.config_data.extract()?;
+        for change in &self.global_overrides {
+            change(&mut self.global_config);
         }
+        Ok(())
     }
 
     /// Just print the plan for debugging purposes.
@@ -97,12 +183,149 @@ impl<'a> Run<'a> {
         } else {
             println!("start: {}", self.plan.start);
         }
-        for (op, file) in self.plan.steps {
-            println!("{}: {} -> {}", op, self.driver.ops[op].name, file);
+        let through = self.plan_through();
+        if !through.is_empty() {
+            println!("through: {}", through.join(", "));
+        }
+        let avoid = self.plan_avoid();
+        if !avoid.is_empty() {
+            println!("avoid: {}", avoid.join(", "));
+        }
+        // Steps usually consume the previous step's output. Mention the inputs when they don't.
+        let join = |files: &[Utf8PathBuf]| {
+            files
+                .iter()
+                .map(|f| f.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut last_file = self.plan.start.as_path();
+        for step in &self.plan.steps {
+            let name = &self.driver.ops[step.op].name;
+            let outputs = join(&step.outputs);
+            if step.inputs == [last_file] {
+                println!("{}: {} -> {}", step.op, name, outputs);
+            } else {
+                let inputs = join(&step.inputs);
+                println!("{}: {} (from {}) -> {}", step.op, name, inputs, outputs);
+            }
+            last_file = step.output();
         }
         if self.plan.stdout {
             println!("-> (stdout)");
         }
+        if self.plan.targets.len() > 1 {
+            let targets: Vec<_> = self.plan.targets.iter().map(|t| t.as_str()).collect();
+            println!("targets: {}", targets.join(", "));
+        }
+        for (op, reason) in &self.plan.skipped {
+            println!("skipped {}: {}", self.driver.ops[*op].name, reason);
+        }
+    }
+
+    /// Print a JSON description of the plan, for consumption by other tools.
+    pub fn show_json(self) {
+        let file_json = |file: &Utf8Path, state: StateRef| FileJson {
+            file: file.to_string(),
+            state: Some(&self.driver.states[state].name),
+        };
+
+        // Describe each step, including the files it consumes. Only the first input and output
+        // have a known state.
+        let files_json = |files: &[Utf8PathBuf], state: StateRef| {
+            files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| match i {
+                    0 => file_json(file, state),
+                    _ => FileJson {
+                        file: file.to_string(),
+                        state: None,
+                    },
+                })
+                .collect()
+        };
+        let steps = self
+            .plan
+            .steps
+            .iter()
+            .map(|step| {
+                let op = &self.driver.ops[step.op];
+                StepJson {
+                    op: &op.name,
+                    inputs: files_json(&step.inputs, op.input),
+                    outputs: files_json(&step.outputs, op.output),
+                }
+            })
+            .collect();
+
+        let plan = PlanJson {
+            input: file_json(&self.plan.start, self.plan.start_state),
+            steps,
+            targets: self.plan.targets.iter().map(|t| t.to_string()).collect(),
+            setups: self
+                .plan_setups()
+                .into_iter()
+                .map(|s| self.driver.setups[s].name.as_ref())
+                .collect(),
+            workdir: self.plan.workdir.to_string(),
+            stdin: self.plan.stdin,
+            stdout: self.plan.stdout,
+            through: self.plan_through(),
+            avoid: self.plan_avoid(),
+            skipped: self
+                .plan
+                .skipped
+                .iter()
+                .map(|(op, reason)| SkippedJson {
+                    op: &self.driver.ops[*op].name,
+                    reason,
+                })
+                .collect(),
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).expect("plan is serializable")
+        );
+    }
+
+    /// The names of the operations that the plan was asked to route through.
+    fn plan_through(&self) -> Vec<&str> {
+        self.plan
+            .through
+            .iter()
+            .map(|op| self.driver.ops[*op].name.as_str())
+            .collect()
+    }
+
+    /// The names of the operations and states that the plan was asked to avoid.
+    fn plan_avoid(&self) -> Vec<&str> {
+        let ops = self
+            .plan
+            .avoid_ops
+            .iter()
+            .map(|op| self.driver.ops[*op].name.as_str());
+        let states = self
+            .plan
+            .avoid_states
+            .iter()
+            .map(|state| self.driver.states[*state].name.as_str());
+        ops.chain(states).collect()
+    }
+
+    /// Get the setups used by the plan's operations, in the order they should be emitted, without
+    /// duplicates.
+    pub(crate) fn plan_setups(&self) -> Vec<SetupRef> {
+        let mut done_setups = HashSet::<SetupRef>::new();
+        let mut setups = vec![];
+        for step in &self.plan.steps {
+            for setup in &self.driver.ops[step.op].setups {
+                if done_setups.insert(*setup) {
+                    setups.push(*setup);
+                }
+            }
+        }
+        setups
     }
 
     /// Print a GraphViz representation of the plan.
@@ -114,11 +337,10 @@ impl<'a> Run<'a> {
         // Record the states and ops that are actually used in the plan.
         let mut states: HashMap<StateRef, String> = HashMap::new();
         let mut ops: HashSet<OpRef> = HashSet::new();
-        let first_op = self.plan.steps[0].0;
-        states.insert(self.driver.ops[first_op].input, self.plan.start.to_string());
-        for (op, file) in &self.plan.steps {
-            states.insert(self.driver.ops[*op].output, file.to_string());
-            ops.insert(*op);
+        states.insert(self.plan.start_state, self.plan.start.to_string());
+        for step in &self.plan.steps {
+            states.insert(self.driver.ops[step.op].output, step.output().to_string());
+            ops.insert(step.op);
         }
 
         // Show all states.
@@ -149,16 +371,25 @@ impl<'a> Run<'a> {
 
     /// Print the `build.ninja` file to stdout.
     pub fn emit_to_stdout(&self) -> EmitResult {
-        self.emit(std::io::stdout())
+        self.emit(std::io::stdout())?;
+        Ok(())
     }
 
-    /// Ensure that a directory exists and write `build.ninja` inside it.
+    /// Ensure that a directory exists and write `build.ninja` inside it. If this creates the
+    /// directory, it is marked as a build directory, so `clean` mode can later remove it.
     pub fn emit_to_dir(&self, dir: &Utf8Path) -> EmitResult {
-        std::fs::create_dir_all(dir)?;
+        self.emit_ninja_file(dir)?;
+        Ok(())
+    }
+
+    fn emit_ninja_file(&self, dir: &Utf8Path) -> Result<Emitter> {
+        workdir::create(dir, &self.driver.name)?;
         let ninja_path = dir.join("build.ninja");
-        let ninja_file = std::fs::File::create(ninja_path)?;
+        let ninja_file = std::fs::File::create(&ninja_path).map_err(Error::at(&ninja_path))?;
 
-        self.emit(ninja_file)
+        let emitter = self.emit(ninja_file)?;
+        emitter.write_added_files(dir)?;
+        Ok(emitter)
     }
 
     /// Emit `build.ninja` to a temporary directory and then actually execute ninja.
@@ -169,69 +400,411 @@ impl<'a> Run<'a> {
 
         // Capture stdin.
         if self.plan.stdin {
-            let stdin_file = std::fs::File::create(self.plan.workdir.join(&self.plan.start))?;
-            std::io::copy(
-                &mut std::io::stdin(),
-                &mut std::io::BufWriter::new(stdin_file),
-            )?;
+            self.capture_stdin(None)?;
+        }
+
+        self.run_ninja(dir)?;
+
+        // Remove the temporary directory unless it already existed at the start *or* the user specified `--keep`.
+        if !self.global_config.keep_build_dir && !stale_dir {
+            std::fs::remove_dir_all(dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the input file for a plan that reads from stdin. The contents are `input`, if it's
+    /// provided, then anything passed to `set_stdin`, or otherwise the process's actual stdin.
+    pub(crate) fn capture_stdin(&self, input: Option<&[u8]>) -> EmitResult {
+        let stdin_path = self.plan.workdir.join(&self.plan.start);
+        let stdin_file = std::fs::File::create(&stdin_path).map_err(Error::at(&stdin_path))?;
+        let mut writer = std::io::BufWriter::new(stdin_file);
+        match input.or(self.stdin_data.as_deref()) {
+            Some(mut bytes) => std::io::copy(&mut bytes, &mut writer)?,
+            None => std::io::copy(&mut std::io::stdin(), &mut writer)?,
+        };
+        Ok(())
+    }
+
+    /// The state of the plan's first target.
+    pub(crate) fn end_state(&self) -> StateRef {
+        match self
+            .plan
+            .steps
+            .iter()
+            .find(|s| Some(s.output()) == self.plan.end())
+        {
+            Some(step) => self.driver.ops[step.op].output,
+            None => self.plan.start_state,
+        }
+    }
+
+    /// Emit `build.ninja` and execute ninja, and then do it again every time one of the build's
+    /// inputs changes. The input files are the plan's start file, any external files that the
+    /// Ninja file refers to, and the config file. A failed build, or a config change that breaks
+    /// the build, is reported and we keep watching. This never returns unless the watch itself
+    /// fails.
+    pub fn emit_and_watch(&mut self, dir: &Utf8Path) -> EmitResult {
+        // We watch the containing directories rather than the files themselves because editors
+        // often save files by replacing them, which would end a watch on the file itself.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mut watched_dirs = HashSet::<PathBuf>::new();
+        let config_path = config::config_path(&self.driver.name)?;
+
+        let mut inputs: HashSet<PathBuf> = [
+            config_path.clone(),
+            self.plan.workdir.join(&self.plan.start).into(),
+        ]
+        .into_iter()
+        .map(|p: PathBuf| p.canonicalize().unwrap_or(p))
+        .collect();
+        loop {
+            if let Err(e) = self.watch_build(dir, &mut inputs) {
+                crate::cli::print_error(&e.into());
+            }
+            for input in &inputs {
+                if let Some(parent) = input.parent() {
+                    if parent.exists() && watched_dirs.insert(parent.to_path_buf()) {
+                        watcher.watch(parent, notify::RecursiveMode::NonRecursive)?;
+                    }
+                }
+            }
+
+            eprintln!("watching for changes...");
+
+            // Wait for a change to one of the inputs, and then wait for things to settle down so
+            // a burst of events only causes one rebuild.
+            let mut changed = HashSet::<PathBuf>::new();
+            while changed.is_empty() {
+                let event = rx
+                    .recv()
+                    .map_err(|_| std::io::Error::other("the file watcher stopped"))??;
+                changed.extend(event.paths.into_iter().filter(|p| inputs.contains(p)));
+            }
+            let settle = std::time::Duration::from_millis(100);
+            while let Ok(event) = rx.recv_timeout(settle) {
+                changed.extend(event?.paths);
+            }
+
+            if changed.contains(&config_path) {
+                if let Err(e) = self.reload_config() {
+                    crate::cli::print_error(&e.into());
+                }
+            }
         }
+    }
 
+    /// Build once in watch mode: emit the Ninja file, add the files it depends on to `inputs`, and
+    /// execute ninja. Ninja itself takes care of only rebuilding the steps affected by a change.
+    fn watch_build(&self, dir: &Utf8Path, inputs: &mut HashSet<PathBuf>) -> EmitResult {
+        let emitter = self.emit_ninja_file(dir)?;
+        inputs.extend(emitter.external_files.iter().map(|f| {
+            let path: PathBuf = self.plan.workdir.join(f).into();
+            path.canonicalize().unwrap_or(path)
+        }));
+        drop(emitter);
+        self.run_ninja(dir)
+    }
+
+    /// Execute ninja in `dir`, which must already contain a `build.ninja`, and print the final
+    /// output to stdout if necessary.
+    fn run_ninja(&self, dir: &Utf8Path) -> EmitResult {
         // Run `ninja` in the working directory.
         let mut cmd = Command::new(&self.global_config.ninja);
-        cmd.current_dir(dir);
-        if self.plan.stdout && !self.global_config.verbose {
-            // When we're printing to stdout, suppress Ninja's output by default.
-            cmd.stdout(std::process::Stdio::null());
+        cmd.current_dir(dir).args(self.global_config.ninja_args());
+        if self.plan.stdout {
+            // When we're printing the output to stdout, send Ninja's progress to stderr so the two
+            // don't get mixed up.
+            cmd.stdout(std::io::stderr());
         }
-        cmd.status()?;
 
-        // Emit stdout.
-        if self.plan.stdout {
-            let stdout_file = std::fs::File::open(self.plan.workdir.join(self.plan.end()))?;
-            std::io::copy(
-                &mut std::io::BufReader::new(stdout_file),
-                &mut std::io::stdout(),
-            )?;
+        // A dry run does not produce any output.
+        let stdout_file = self
+            .plan
+            .end()
+            .filter(|_| self.plan.stdout && !self.global_config.dry_run);
+        let stream = stdout_file.is_some() && self.global_config.stream_stdout;
+        let status = if let (true, Some(file)) = (stream, stdout_file) {
+            run_and_stream(&mut cmd, dir, file, std::io::stdout())?
+        } else {
+            cmd.status()?
+        };
+        if !status.success() {
+            return Err(Error::Exec {
+                cmd: self.global_config.ninja.clone(),
+                status,
+            });
         }
 
-        // Remove the temporary directory unless it already existed at the start *or* the user specified `--keep`.
-        if !self.global_config.keep_build_dir && !stale_dir {
-            std::fs::remove_dir_all(dir)?;
+        // Emit stdout, unless we already did while Ninja ran.
+        if let (false, Some(file)) = (stream, stdout_file) {
+            copy_to_stdout(&self.plan.workdir.join(file))?;
         }
 
         Ok(())
     }
 
-    fn emit<T: Write + 'static>(&self, out: T) -> EmitResult {
+    fn emit<T: Write + 'static>(&self, out: T) -> Result<Emitter> {
         let mut emitter = Emitter::new(out, self.config_data.clone(), self.plan.workdir.clone());
 
         // Emit the setup for each operation used in the plan, only once.
-        let mut done_setups = HashSet::<SetupRef>::new();
-        for (op, _) in &self.plan.steps {
-            for setup in &self.driver.ops[*op].setups {
-                if done_setups.insert(*setup) {
-                    let setup = &self.driver.setups[*setup];
-                    writeln!(emitter.out, "# {}", setup.name)?;
-                    setup.emit.setup(&mut emitter)?;
-                    writeln!(emitter.out)?;
-                }
-            }
+        for setup in self.plan_setups() {
+            self.emit_setup(&mut emitter, setup)?;
         }
 
-        // Emit the build commands for each step in the plan.
+        // Emit the build commands for each step in the plan, with dependencies first.
         emitter.comment("build targets")?;
-        let mut last_file = &self.plan.start;
-        for (op, out_file) in &self.plan.steps {
-            let op = &self.driver.ops[*op];
-            op.emit
-                .build(&mut emitter, last_file.as_str(), out_file.as_str())?;
-            last_file = out_file;
+        for index in self.plan.topological_order()? {
+            self.emit_step(&mut emitter, &self.plan.steps[index])?;
         }
         writeln!(emitter.out)?;
 
-        // Mark the last file as the default target.
-        writeln!(emitter.out, "default {}", last_file)?;
+        // Build all the targets by default.
+        write!(emitter.out, "default")?;
+        for target in &self.plan.targets {
+            write!(emitter.out, " {}", target)?;
+        }
+        writeln!(emitter.out)?;
+
+        Ok(emitter)
+    }
+}
 
+impl Run<'_> {
+    /// Emit the Ninja code for one setup, under a comment with its name.
+    pub(crate) fn emit_setup(&self, emitter: &mut Emitter, setup: SetupRef) -> EmitResult {
+        let setup = &self.driver.setups[setup];
+        writeln!(emitter.out, "# {}", setup.name)?;
+        setup.emit.setup(emitter).map_err(|err| Error::Setup {
+            name: setup.name.clone(),
+            err: Box::new(err),
+        })?;
+        writeln!(emitter.out)?;
+        Ok(())
+    }
+
+    /// Emit the build commands for one step of the plan.
+    pub(crate) fn emit_step(&self, emitter: &mut Emitter, step: &Step) -> EmitResult {
+        let op = &self.driver.ops[step.op];
+        let inputs: Vec<_> = step.inputs.iter().map(|f| f.as_str()).collect();
+        let outputs: Vec<_> = step.outputs.iter().map(|f| f.as_str()).collect();
+        op.emit
+            .build_step(emitter, &inputs, &outputs)
+            .map_err(|err| Error::Op {
+                name: op.name.clone(),
+                err: Box::new(err),
+            })
+    }
+}
+
+/// Copy a file to stdout.
+fn copy_to_stdout(path: &Utf8Path) -> Result<()> {
+    copy_to(path, &mut std::io::stdout())
+}
+
+/// Copy a file to a writer.
+fn copy_to(path: &Utf8Path, out: &mut dyn Write) -> Result<()> {
+    let file = std::fs::File::open(path).map_err(Error::at(path))?;
+    std::io::copy(&mut std::io::BufReader::new(file), out)?;
+    Ok(())
+}
+
+/// Remove a file, if it exists.
+#[cfg(unix)]
+fn remove_if_exists(path: &Utf8Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::at(path)(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Create a FIFO at `path` with the modification time `mtime`.
+#[cfg(unix)]
+fn make_fifo(path: &Utf8Path, mtime: std::time::SystemTime) -> std::io::Result<()> {
+    let c_path = std::ffi::CString::new(path.as_str())?;
+    // SAFETY: `c_path` is a valid, NUL-terminated string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let since = mtime
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: since.as_secs() as libc::time_t,
+            tv_nsec: since.subsec_nanos() as _,
+        },
+    ];
+    // SAFETY: `c_path` is valid and `times` has the two entries `utimensat` reads.
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Clear `O_NONBLOCK` on a file.
+#[cfg(unix)]
+fn set_blocking(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is an open file descriptor for as long as `file` lives.
+    let ok = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) == 0
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Run Ninja while forwarding the output file `output` in `dir` to `out` (normally stdout) as it
+/// is written.
+///
+/// The file is set aside and replaced with a FIFO, so the command that builds it writes into a
+/// pipe that we read. The FIFO gets the old file's modification time, so Ninja decides whether the
+/// step is up to date just as it would have. What comes through the FIFO is also saved, and
+/// becomes the new file if the command succeeds. If the step didn't run, the old file comes back
+/// and is copied to `out`. If the command replaced the FIFO instead of writing to it, the file it
+/// left is copied to `out` instead.
+#[cfg(unix)]
+fn run_and_stream<W: Write + Send>(
+    cmd: &mut Command,
+    dir: &Utf8Path,
+    output: &Utf8Path,
+    mut out: W,
+) -> Result<ExitStatus> {
+    use std::io::Read;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    let path = dir.join(output);
+    let old = Utf8PathBuf::from(format!("{}.stream-old", path));
+    let new = Utf8PathBuf::from(format!("{}.stream-new", path));
+
+    let mtime = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    if mtime.is_some() {
+        std::fs::rename(&path, &old).map_err(Error::at(&path))?;
+    }
+    if let Err(e) = make_fifo(&path, mtime.unwrap_or(std::time::UNIX_EPOCH)) {
+        if mtime.is_some() {
+            std::fs::rename(&old, &path).map_err(Error::at(&old))?;
+        }
+        return Err(Error::at(&path)(e));
+    }
+
+    // Open both ends of the FIFO ourselves. While we hold the write end, reading waits for the
+    // command's output instead of finding the FIFO empty, even before the command opens it.
+    let mut reader = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)
+        .map_err(Error::at(&path))?;
+    let writer = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .map_err(Error::at(&path))?;
+    set_blocking(&reader)?;
+    let mut saved = std::fs::File::create(&new).map_err(Error::at(&new))?;
+    let log_before = report::ninja_log_before(dir);
+    let (status, forwarded) = std::thread::scope(|scope| {
+        let forward = scope.spawn(|| -> std::io::Result<()> {
+            let mut buf = [0; 8192];
+            loop {
+                let len = reader.read(&mut buf)?;
+                if len == 0 {
+                    return Ok(());
+                }
+                out.write_all(&buf[..len])?;
+                out.flush()?;
+                saved.write_all(&buf[..len])?;
+            }
+        });
+        let status = cmd.status();
+        drop(writer);
+        let forwarded = forward
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("forwarding the output failed")));
+        (status, forwarded)
+    });
+
+    // Put the right file back in place. Ninja's log tells us whether the step ran.
+    let ran = report::read_ninja_log(dir, &log_before)?
+        .iter()
+        .any(|entry| entry.output == output);
+    let succeeded = status.as_ref().is_ok_and(|s| s.success());
+    let still_fifo = std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_fifo());
+    if still_fifo {
+        std::fs::remove_file(&path).map_err(Error::at(&path))?;
+        if ran && succeeded {
+            std::fs::rename(&new, &path).map_err(Error::at(&new))?;
+            remove_if_exists(&old)?;
+        } else {
+            remove_if_exists(&new)?;
+            if mtime.is_some() {
+                std::fs::rename(&old, &path).map_err(Error::at(&old))?;
+            }
+            if !ran && succeeded {
+                copy_to(&path, &mut out)?;
+            }
+        }
+    } else {
+        remove_if_exists(&new)?;
+        remove_if_exists(&old)?;
+        if succeeded {
+            copy_to(&path, &mut out)?;
+        }
+    }
+
+    forwarded?;
+    Ok(status?)
+}
+
+/// Without FIFOs, just copy the output to stdout once Ninja is done.
+#[cfg(not(unix))]
+fn run_and_stream<W: Write + Send>(
+    cmd: &mut Command,
+    dir: &Utf8Path,
+    output: &Utf8Path,
+    mut out: W,
+) -> Result<ExitStatus> {
+    let status = cmd.status()?;
+    if status.success() {
+        copy_to(&dir.join(output), &mut out)?;
+    }
+    Ok(status)
+}
+
+/// Find the configuration keys that a driver's setups and operations look up, by emitting all of
+/// them without actually producing a Ninja file.
+pub fn config_keys(driver: &Driver) -> BTreeSet<String> {
+    let mut emitter = Emitter::new_probe();
+    for setup in driver.setups.values() {
+        // Errors are not interesting here; we just want to see as many keys as we can.
+        let _ = setup.emit.setup(&mut emitter);
+    }
+    for op in driver.ops.values() {
+        let _ = op.emit.build(&mut emitter, "input", "output");
+    }
+    emitter.probe.unwrap().into_inner()
+}
+
+/// A writer that keeps everything in a buffer we can still read after the emitter is done.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
 }
@@ -240,10 +813,20 @@ pub struct Emitter {
     pub out: Box<dyn Write>,
     pub config_data: figment::Figment,
     pub workdir: Utf8PathBuf,
+
+    /// Files outside the build directory that the Ninja file refers to.
+    pub external_files: Vec<Utf8PathBuf>,
+
+    /// Files to write to the build directory, with their contents, if the Ninja file is written
+    /// there too.
+    pub(crate) added_files: Vec<(String, Vec<u8>)>,
+
+    /// When probing, the configuration keys that have been looked up so far.
+    pub(crate) probe: Option<RefCell<BTreeSet<String>>>,
 }
 
 impl Emitter {
-    fn new<T: Write + 'static>(
+    pub(crate) fn new<T: Write + 'static>(
         out: T,
         config_data: figment::Figment,
         workdir: Utf8PathBuf,
@@ -252,18 +835,42 @@ impl Emitter {
             out: Box::new(out),
             config_data,
             workdir,
+            external_files: vec![],
+            added_files: vec![],
+            probe: None,
+        }
+    }
+
+    /// Create an emitter that discards its output and records the configuration keys that are
+    /// looked up. Missing configuration values are not an error: instead, they are replaced with
+    /// the key name itself so emission can continue.
+    pub(crate) fn new_probe() -> Self {
+        Self {
+            probe: Some(Default::default()),
+            ..Self::new(std::io::sink(), Default::default(), ".".into())
+        }
+    }
+
+    /// Record a configuration lookup when probing.
+    fn probe_key(&self, key: &str) {
+        if let Some(keys) = &self.probe {
+            keys.borrow_mut().insert(key.to_string());
         }
     }
 
     /// Fetch a configuration value, or panic if it's missing.
-    pub fn config_val(&self, key: &str) -> Result<String, EmitError> {
-        self.config_data
-            .extract_inner::<String>(key)
-            .map_err(|_| EmitError::MissingConfig(key.to_string()))
+    pub fn config_val(&self, key: &str) -> Result<String> {
+        self.probe_key(key);
+        match self.config_data.extract_inner::<String>(key) {
+            Ok(val) => Ok(val),
+            Err(_) if self.probe.is_some() => Ok(key.to_string()),
+            Err(_) => Err(Error::MissingConfig(key.to_string())),
+        }
     }
 
     /// Fetch a configuration value, using a default if it's missing.
     pub fn config_or(&self, key: &str, default: &str) -> String {
+        self.probe_key(key);
         self.config_data
             .extract_inner::<String>(key)
             .unwrap_or_else(|_| default.into())
@@ -292,6 +899,26 @@ impl Emitter {
         writeln!(self.out, "  command = {}", command)
     }
 
+    /// Declare a depfile for the rule just emitted. The rule's command must write a Makefile-style
+    /// file at `path` listing extra dependencies of `$out`, such as included files, which Ninja
+    /// then tracks. With a `deps` format like `gcc`, Ninja records the dependencies in its own
+    /// database and removes the depfile.
+    pub fn depfile(&mut self, path: &str, deps: Option<&str>) -> std::io::Result<()> {
+        self.arg("depfile", path)?;
+        if let Some(deps) = deps {
+            self.arg("deps", deps)?;
+        }
+        Ok(())
+    }
+
+    /// Emit a Ninja pool definition, which limits how many of the commands assigned to it can run
+    /// at once. Assign a rule to the pool with `arg("pool", name)`. Ninja also has a built-in
+    /// `console` pool of depth 1, whose commands get direct access to the terminal.
+    pub fn pool(&mut self, name: &str, depth: usize) -> std::io::Result<()> {
+        writeln!(self.out, "pool {}", name)?;
+        writeln!(self.out, "  depth = {}", depth)
+    }
+
     /// Emit a simple Ninja build command with one dependency.
     pub fn build(&mut self, rule: &str, input: &str, output: &str) -> std::io::Result<()> {
         self.build_cmd(&[output], rule, &[input], &[])
@@ -304,11 +931,30 @@ impl Emitter {
         rule: &str,
         deps: &[&str],
         implicit_deps: &[&str],
+    ) -> std::io::Result<()> {
+        self.build_cmd_with_outputs(targets, &[], rule, deps, implicit_deps)
+    }
+
+    /// Emit a Ninja build command that also produces implicit outputs: files that the command
+    /// writes but that do not appear in `$out`.
+    pub fn build_cmd_with_outputs(
+        &mut self,
+        targets: &[&str],
+        implicit_targets: &[&str],
+        rule: &str,
+        deps: &[&str],
+        implicit_deps: &[&str],
     ) -> std::io::Result<()> {
         write!(self.out, "build")?;
         for target in targets {
             write!(self.out, " {}", target)?;
         }
+        if !implicit_targets.is_empty() {
+            write!(self.out, " |")?;
+            for target in implicit_targets {
+                write!(self.out, " {}", target)?;
+            }
+        }
         write!(self.out, ": {}", rule)?;
         for dep in deps {
             write!(self.out, " {}", dep)?;
@@ -329,18 +975,31 @@ impl Emitter {
         Ok(())
     }
 
-    /// Add a file to the build directory.
-    pub fn add_file(&self, name: &str, contents: &[u8]) -> std::io::Result<()> {
-        let path = self.workdir.join(name);
-        std::fs::write(path, contents)?;
+    /// Add a file to the build directory. The file is only written when the Ninja file goes to a
+    /// build directory, so printing the Ninja code or the plan leaves the filesystem alone.
+    pub fn add_file(&mut self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        if self.probe.is_none() {
+            self.added_files.push((name.to_string(), contents.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Write the files from `add_file` to the build directory `dir`.
+    pub(crate) fn write_added_files(&self, dir: &Utf8Path) -> Result<()> {
+        for (name, contents) in &self.added_files {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).map_err(Error::at(&path))?;
+        }
         Ok(())
     }
 
     /// Get a path to an external file. The input `path` may be relative to our original
     /// invocation; we make it relative to the build directory so it can safely be used in the
     /// Ninja file.
-    pub fn external_path(&self, path: &Utf8Path) -> Utf8PathBuf {
-        relative_path(path, &self.workdir)
+    pub fn external_path(&mut self, path: &Utf8Path) -> Result<Utf8PathBuf> {
+        let path = relative_path(path, &self.workdir)?;
+        self.external_files.push(path.clone());
+        Ok(path)
     }
 
     /// Add a variable parameter to a rule or build command.
@@ -349,3 +1008,134 @@ impl Emitter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::DriverBuilder;
+    use crate::testing::{default_config, request, TempDir};
+
+    fn driver() -> Driver {
+        let mut bld = DriverBuilder::new("runtest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let setup = bld.setup("tools", |e| {
+            e.add_file("copy.sh", b"cp \"$1\" \"$2\"")?;
+            e.rule("copy", "sh copy.sh $in $out")?;
+            Ok(())
+        });
+        bld.rule(&[setup], a, b, "copy");
+        bld.build().unwrap()
+    }
+
+    /// Plan a conversion from `in.a` to `out.b`, both in `dir`, which is also the build directory.
+    fn run<'a>(driver: &'a Driver, dir: &Utf8Path) -> Run<'a> {
+        let input = dir.join("in.a");
+        let output = dir.join("out.b");
+        let req = request(driver, input.as_str(), &[output.as_str()], dir);
+        Run::with_config(driver, driver.plan(req).unwrap(), default_config()).unwrap()
+    }
+
+    #[test]
+    fn added_files_only_go_to_build_dir() {
+        let driver = driver();
+        let tmp = TempDir::new("added-files");
+        let run = run(&driver, &tmp.0);
+
+        run.emit(std::io::sink()).unwrap();
+        assert!(!tmp.0.join("copy.sh").exists());
+
+        run.emit_to_dir(&tmp.0).unwrap();
+        assert!(tmp.0.join("copy.sh").exists());
+    }
+
+    #[test]
+    fn extra_step_files_need_a_capable_op() {
+        let driver = driver();
+        let tmp = TempDir::new("extra-files");
+        let mut run = run(&driver, &tmp.0);
+        run.plan.steps[0].inputs.push("data.txt".into());
+        run.plan.steps[0].outputs.push("log.txt".into());
+        let Err(Error::Op { name, err }) = run.emit(std::io::sink()) else {
+            panic!("expected an error from the operation");
+        };
+        assert_eq!(name, "copy");
+        assert!(matches!(*err, Error::ExtraFiles(files) if files == ["data.txt", "log.txt"]));
+    }
+
+    /// Stream `out.txt` in `dir` while running a shell command in place of Ninja. Return what was
+    /// streamed, after checking that the file ends up with the same contents.
+    #[cfg(unix)]
+    fn stream(dir: &Utf8Path, script: &str) -> String {
+        let mut cmd = Command::new("sh");
+        cmd.current_dir(dir).args(["-c", script]);
+        let mut out = vec![];
+        assert!(run_and_stream(&mut cmd, dir, "out.txt".into(), &mut out)
+            .unwrap()
+            .success());
+        for leftover in ["out.txt.stream-old", "out.txt.stream-new"] {
+            assert!(!dir.join(leftover).exists());
+        }
+        let streamed = String::from_utf8(out).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+            streamed
+        );
+        streamed
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streamed_output_ends_up_in_place() {
+        let tmp = TempDir::new("stream");
+        let out = tmp.0.join("out.txt");
+        std::fs::write(&out, "old\n").unwrap();
+        let mtime = std::fs::metadata(&out).unwrap().modified().unwrap();
+
+        // A step that doesn't run leaves the old file, with its modification time.
+        assert_eq!(stream(&tmp.0, "true"), "old\n");
+        assert_eq!(std::fs::metadata(&out).unwrap().modified().unwrap(), mtime);
+
+        // A step that writes through the FIFO saves what it wrote.
+        let log = r#"printf '0\t1\t0\tout.txt\t0\n' >> .ninja_log"#;
+        let script = format!("echo new > out.txt && {}", log);
+        assert_eq!(stream(&tmp.0, &script), "new\n");
+
+        // So does a step that replaces the FIFO with a file of its own.
+        let script = format!("echo moved > tmp && mv tmp out.txt && {}", log);
+        assert_eq!(stream(&tmp.0, &script), "moved\n");
+    }
+
+    #[test]
+    fn pools_limit_rules() {
+        let capture = Capture::default();
+        let mut emitter = Emitter::new(capture.clone(), default_config(), ".".into());
+        emitter.pool("sim", 2).unwrap();
+        emitter.rule("simulate", "sim $in > $out").unwrap();
+        emitter.arg("pool", "sim").unwrap();
+        drop(emitter);
+        assert_eq!(
+            String::from_utf8(capture.0.borrow().clone()).unwrap(),
+            "pool sim\n  depth = 2\nrule simulate\n  command = sim $in > $out\n  pool = sim\n"
+        );
+    }
+
+    #[test]
+    fn watch_build_recovers_after_failure() {
+        let driver = driver();
+        let tmp = TempDir::new("watch");
+        let mut run = run(&driver, &tmp.0);
+        let mut inputs = HashSet::new();
+
+        // The first build fails, which watch mode reports without giving up.
+        run.global_config.ninja = "false".into();
+        let err = run.watch_build(&tmp.0, &mut inputs).unwrap_err();
+        assert!(matches!(err, Error::Exec { .. }), "{}", err);
+
+        // After a fix, the next build succeeds.
+        std::fs::remove_file(tmp.0.join("build.ninja")).unwrap();
+        run.global_config.ninja = "true".into();
+        run.watch_build(&tmp.0, &mut inputs).unwrap();
+        assert!(tmp.0.join("build.ninja").exists());
+    }
+}
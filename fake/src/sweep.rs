@@ -0,0 +1,316 @@
+//! Parameter sweeps, which build a plan once for each of several values of a configuration key.
+//!
+//! All the values share one Ninja build. The steps that don't depend on the swept key, directly or
+//! through an earlier step, appear once in `build.ninja`. Everything else is emitted again for each
+//! value in its own `sweepN.ninja`, which `build.ninja` includes with `subninja`. The files those
+//! steps produce get a `sweepN-` prefix so the values don't overwrite each other's results.
+
+use crate::config;
+use crate::driver::{SetupRef, Step};
+use crate::error::{Error, Result};
+use crate::run::{EmitResult, Emitter, Run};
+use crate::workdir;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::process::Command;
+use std::time::Instant;
+
+/// A configuration key and the values to try for it.
+pub struct Sweep {
+    pub key: String,
+    pub values: Vec<String>,
+}
+
+/// The outcome of building the plan with one value.
+#[derive(Serialize)]
+pub struct SweepRun {
+    pub value: String,
+    pub success: bool,
+
+    /// The build tool's exit code, if it exited normally.
+    pub exit_code: Option<i32>,
+
+    /// Wall-clock time for the build. This includes any shared steps that this value was the first
+    /// to need.
+    pub seconds: f64,
+
+    /// The plan's targets for this value, relative to the current directory.
+    pub outputs: Vec<Utf8PathBuf>,
+}
+
+/// The results of a sweep, in the order of the values.
+#[derive(Serialize)]
+pub struct SweepResult {
+    pub key: String,
+    pub runs: Vec<SweepRun>,
+}
+
+impl SweepResult {
+    pub fn success(&self) -> bool {
+        self.runs.iter().all(|r| r.success)
+    }
+
+    /// Print a table with the status, time, and outputs for each value.
+    pub fn write_table(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let width = self
+            .runs
+            .iter()
+            .map(|r| r.value.len())
+            .chain([self.key.len()])
+            .max()
+            .unwrap_or(0);
+        writeln!(
+            out,
+            "{:<width$}  {:>9}  {:<10}  outputs",
+            self.key, "seconds", "status"
+        )?;
+        for run in &self.runs {
+            let status = match run.exit_code {
+                Some(0) => "ok".to_string(),
+                Some(code) => format!("failed ({})", code),
+                None => "killed".to_string(),
+            };
+            let outputs: Vec<_> = run.outputs.iter().map(|o| o.as_str()).collect();
+            writeln!(
+                out,
+                "{:<width$}  {:>9.3}  {:<10}  {}",
+                run.value,
+                run.seconds,
+                status,
+                outputs.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+/// The name of a file produced by a step that depends on the swept key, for the value at `index`.
+fn sweep_file(path: &Utf8Path, index: usize) -> Utf8PathBuf {
+    let name = path.file_name().unwrap_or(path.as_str());
+    path.with_file_name(format!("sweep{}-{}", index, name))
+}
+
+impl Run<'_> {
+    /// Find the setups and steps that depend on the configuration `key` (or, if it's a table, any
+    /// key inside it). Setups depend on it if they look it up. Steps depend on it if their
+    /// operation looks it up, if they use a dependent setup, or if they consume the output of a
+    /// dependent step. Return the dependent setups and a flag for each step.
+    fn sweep_dependents(&self, key: &str) -> Result<(HashSet<SetupRef>, Vec<bool>)> {
+        let reads_key = |emit: &dyn Fn(&mut Emitter) -> EmitResult| {
+            let mut emitter = Emitter::new_probe();
+            // As with `run::config_keys`, errors just mean we see fewer lookups.
+            let _ = emit(&mut emitter);
+            let keys = emitter.probe.unwrap().into_inner();
+            let prefix = format!("{}.", key);
+            keys.iter().any(|k| k == key || k.starts_with(&prefix))
+        };
+
+        let setups: HashSet<SetupRef> = self
+            .plan_setups()
+            .into_iter()
+            .filter(|&setup| reads_key(&|e| self.driver.setups[setup].emit.setup(e)))
+            .collect();
+        let mut dependent = vec![false; self.plan.steps.len()];
+        for index in self.plan.topological_order()? {
+            let step = &self.plan.steps[index];
+            dependent[index] = self.driver.ops[step.op]
+                .setups
+                .iter()
+                .any(|s| setups.contains(s))
+                || self.plan.dependencies(index).iter().any(|&d| dependent[d])
+                || reads_key(&|e| self.emit_step(e, step));
+        }
+        Ok((setups, dependent))
+    }
+
+    /// Write `build.ninja` for a sweep, along with a `sweepN.ninja` for each value, to `dir`.
+    /// Return the targets for each value.
+    pub fn emit_sweep(&self, sweep: &Sweep, dir: &Utf8Path) -> Result<Vec<Vec<Utf8PathBuf>>> {
+        workdir::create(dir, &self.driver.name)?;
+        let (dependent_setups, dependent) = self.sweep_dependents(&sweep.key)?;
+        let order = self.plan.topological_order()?;
+        let setups = self.plan_setups();
+
+        // The files produced by dependent steps, which get a different name for each value.
+        let renamed: HashSet<&Utf8Path> = order
+            .iter()
+            .filter(|&&i| dependent[i])
+            .flat_map(|&i| self.plan.steps[i].outputs.iter().map(|f| f.as_path()))
+            .collect();
+
+        // The shared part of the build.
+        let ninja_path = dir.join("build.ninja");
+        let ninja_file = std::fs::File::create(&ninja_path).map_err(Error::at(&ninja_path))?;
+        let mut emitter = Emitter::new(
+            ninja_file,
+            self.config_data.clone(),
+            self.plan.workdir.clone(),
+        );
+        for &setup in setups.iter().filter(|s| !dependent_setups.contains(s)) {
+            self.emit_setup(&mut emitter, setup)?;
+        }
+        emitter.comment("shared build targets")?;
+        for &index in order.iter().filter(|&&i| !dependent[i]) {
+            self.emit_step(&mut emitter, &self.plan.steps[index])?;
+        }
+        writeln!(emitter.out)?;
+
+        // The rest of the build, once for each value.
+        emitter.comment(&format!("sweep over {}", sweep.key))?;
+        let mut targets = vec![];
+        for (i, value) in sweep.values.iter().enumerate() {
+            let name = format!("sweep{}.ninja", i);
+            let path = dir.join(&name);
+            let file = std::fs::File::create(&path).map_err(Error::at(&path))?;
+            let config_data = config::set_value(self.config_data.clone(), &sweep.key, value);
+            let mut sub = Emitter::new(file, config_data, self.plan.workdir.clone());
+            sub.comment(&format!("{} = {}", sweep.key, value))?;
+            writeln!(sub.out)?;
+            for &setup in setups.iter().filter(|s| dependent_setups.contains(s)) {
+                self.emit_setup(&mut sub, setup)?;
+            }
+
+            let rename = |file: &Utf8PathBuf| {
+                if renamed.contains(file.as_path()) {
+                    sweep_file(file, i)
+                } else {
+                    file.clone()
+                }
+            };
+            sub.comment("build targets")?;
+            for &index in order.iter().filter(|&&i| dependent[i]) {
+                let step = &self.plan.steps[index];
+                let step = Step {
+                    op: step.op,
+                    inputs: step.inputs.iter().map(rename).collect(),
+                    outputs: step.outputs.iter().map(rename).collect(),
+                };
+                self.emit_step(&mut sub, &step)?;
+            }
+
+            writeln!(emitter.out, "subninja {}", name)?;
+            targets.push(self.plan.targets.iter().map(rename).collect::<Vec<_>>());
+        }
+        writeln!(emitter.out)?;
+
+        // Build every value's targets by default. Shared targets only need to appear once.
+        let mut defaults: Vec<&Utf8PathBuf> = vec![];
+        for target in targets.iter().flatten() {
+            if !defaults.contains(&target) {
+                defaults.push(target);
+            }
+        }
+        write!(emitter.out, "default")?;
+        for target in defaults {
+            write!(emitter.out, " {}", target)?;
+        }
+        writeln!(emitter.out)?;
+
+        Ok(targets)
+    }
+
+    /// Build the plan once for each value in a sweep, in `dir`, and collect the results. A failing
+    /// value does not stop the sweep. The build directory is never removed, since it's where the
+    /// results are.
+    pub fn run_sweep(&self, sweep: &Sweep, dir: &Utf8Path) -> Result<SweepResult> {
+        let targets = self.emit_sweep(sweep, dir)?;
+        if self.plan.stdin {
+            self.capture_stdin(None)?;
+        }
+
+        let mut runs = vec![];
+        for (value, targets) in sweep.values.iter().zip(targets) {
+            eprintln!("{} = {}", sweep.key, value);
+            let start_time = Instant::now();
+
+            // The results go in a table on stdout, so Ninja's progress goes to stderr.
+            let status = Command::new(&self.global_config.ninja)
+                .current_dir(dir)
+                .args(self.global_config.ninja_args())
+                .args(&targets)
+                .stdout(std::io::stderr())
+                .status()?;
+            runs.push(SweepRun {
+                value: value.clone(),
+                success: status.success(),
+                exit_code: status.code(),
+                seconds: start_time.elapsed().as_secs_f64(),
+                outputs: targets
+                    .iter()
+                    .map(|t| workdir::join(&self.plan.workdir, t))
+                    .collect(),
+            });
+        }
+        Ok(SweepResult {
+            key: sweep.key.clone(),
+            runs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::DriverBuilder;
+    use crate::testing::{default_config, request, TempDir};
+
+    /// A driver with two steps from `a` to `c`, where only the second reads `opt.level`.
+    fn driver() -> crate::driver::Driver {
+        let mut bld = DriverBuilder::new("sweeptest");
+        let a = bld.state("a", &["a"]);
+        let b = bld.state("b", &["b"]);
+        let c = bld.state("c", &["c"]);
+        let setup = bld.setup("tools", |e| {
+            e.rule("copy", "cp $in $out")?;
+            e.rule("optimize", "opt -O$level $in > $out")?;
+            Ok(())
+        });
+        bld.rule(&[setup], a, b, "copy");
+        bld.op("optimize", &[setup], b, c, |e, input, output| {
+            e.build_cmd(&[output], "optimize", &[input], &[])?;
+            e.config_var("level", "opt.level")?;
+            Ok(())
+        });
+        bld.build().unwrap()
+    }
+
+    #[test]
+    fn dependent_steps_are_swept() {
+        let driver = driver();
+        let tmp = TempDir::new("sweep");
+        let dir = &tmp.0;
+        let (input, output) = (dir.join("in.a"), dir.join("out.c"));
+        let req = request(&driver, input.as_str(), &[output.as_str()], dir);
+        let run = Run::with_config(&driver, driver.plan(req).unwrap(), default_config()).unwrap();
+
+        let (_, dependent) = run.sweep_dependents("opt").unwrap();
+        assert_eq!(dependent, [false, true]);
+
+        let sweep = Sweep {
+            key: "opt.level".to_string(),
+            values: vec!["1".to_string(), "2".to_string()],
+        };
+        let targets = run.emit_sweep(&sweep, dir).unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        let (shared, sweep0, sweep1) = (
+            read("build.ninja"),
+            read("sweep0.ninja"),
+            read("sweep1.ninja"),
+        );
+
+        assert!(shared.contains(": copy "));
+        assert!(!shared.contains(": optimize "));
+        assert!(sweep0.contains("build sweep0-out.c: optimize "));
+        assert!(sweep0.contains("level = 1"));
+        assert!(sweep1.contains("build sweep1-out.c: optimize "));
+        assert!(sweep1.contains("level = 2"));
+        assert_eq!(targets, [["sweep0-out.c"], ["sweep1-out.c"]]);
+    }
+}
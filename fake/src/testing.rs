@@ -0,0 +1,79 @@
+//! Helpers for driver authors to check their drivers in unit tests.
+
+use crate::driver::{Driver, DriverBuilder};
+
+/// Build a driver, panicking with a list of its problems if it is invalid.
+pub fn assert_valid(bld: DriverBuilder) -> Driver {
+    bld.build().unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Check that the driver can convert between two states, given by name, and panic with an
+/// explanation if it can't.
+pub fn assert_path(driver: &Driver, from: &str, to: &str) {
+    let start = driver
+        .get_state(from)
+        .unwrap_or_else(|| panic!("unknown state {}", from));
+    let end = driver
+        .get_state(to)
+        .unwrap_or_else(|| panic!("unknown state {}", to));
+    let excluded = Default::default();
+    if driver.find_path(start, end, &[], &excluded).is_none() {
+        panic!("{}", driver.explain_no_path(start, end, &[], &excluded));
+    }
+}
+
+/// A scratch directory for one of our own tests, removed when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(pub camino::Utf8PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Make an empty directory whose name includes `name`, which should be unique among tests.
+    pub fn new(name: &str) -> Self {
+        let base = camino::Utf8PathBuf::from_path_buf(std::env::temp_dir()).unwrap();
+        let dir = base.join(format!("fake-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A request to convert `input` to the `outputs` in `workdir`, with states from the extensions.
+#[cfg(test)]
+pub(crate) fn request(
+    driver: &Driver,
+    input: &str,
+    outputs: &[&str],
+    workdir: &camino::Utf8Path,
+) -> crate::driver::Request {
+    crate::driver::Request {
+        start_state: driver.guess_state(input.into()).unwrap(),
+        targets: outputs
+            .iter()
+            .map(|&file| crate::driver::Target {
+                state: driver.guess_state(file.into()).unwrap(),
+                file: Some(file.into()),
+            })
+            .collect(),
+        start_file: Some(input.into()),
+        through: vec![],
+        workdir: workdir.to_path_buf(),
+        excluded: Default::default(),
+        avoid_ops: vec![],
+        avoid_states: vec![],
+    }
+}
+
+/// The default configuration, without reading the config file.
+#[cfg(test)]
+pub(crate) fn default_config() -> figment::Figment {
+    use figment::providers::Serialized;
+    figment::Figment::from(Serialized::defaults(crate::config::GlobalConfig::default()))
+}
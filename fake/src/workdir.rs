@@ -0,0 +1,172 @@
+use crate::config::GlobalConfig;
+use crate::error::{Error, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use std::env;
+use std::time::Duration;
+
+/// Join a path in the build directory onto the directory, without any `..` that would just undo
+/// the join, like `build/../out.txt`.
+pub(crate) fn join(workdir: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    let mut joined = workdir.to_path_buf();
+    for component in path.components() {
+        match component {
+            Utf8Component::ParentDir
+                if matches!(
+                    joined.components().next_back(),
+                    Some(Utf8Component::Normal(_))
+                ) =>
+            {
+                joined.pop();
+            }
+            _ => joined.push(component),
+        }
+    }
+    if joined.as_str().is_empty() {
+        joined.push(".");
+    }
+    joined
+}
+
+/// The directory that holds persistent build directories for the driver called `name`.
+pub(crate) fn cache_root(name: &str, config: &GlobalConfig) -> Result<Utf8PathBuf> {
+    if let Some(dir) = &config.cache_dir {
+        return Ok(dir.into());
+    }
+
+    // By default, this is `~/.cache/driver_name`.
+    let cache_base = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) => dir,
+        Err(_) => env::var("HOME").map_err(|_| Error::NoConfigDir)? + "/.cache",
+    };
+    Ok(Utf8Path::new(&cache_base).join(name))
+}
+
+/// A 64-bit FNV-1a hash, which (unlike `std`'s hashers) is stable across Rust versions.
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The persistent build directory for an input file under the cache root. Every run on the same
+/// file uses the same directory, so Ninja only needs to rebuild what changed.
+pub(crate) fn input_dir(root: &Utf8Path, input: &Utf8Path) -> Result<Utf8PathBuf> {
+    let path = input.canonicalize_utf8().map_err(Error::at(input))?;
+    let stem = path.file_stem().unwrap_or("input");
+    Ok(root.join(format!("{}-{:016x}", stem, fnv1a(path.as_str().as_bytes()))))
+}
+
+/// The file that marks a directory as a build directory, containing the name of the driver that
+/// created it. We only ever delete directories that have this marker.
+const MARKER: &str = ".fake-workdir";
+
+/// Create a build directory for the driver called `name`, if necessary, and mark it. Reusing a
+/// marked directory refreshes the marker, which records when the directory was last used. A
+/// directory that already existed without a marker, like `.`, is not ours, so it stays unmarked.
+pub(crate) fn create(dir: &Utf8Path, name: &str) -> Result<()> {
+    let path = dir.join(MARKER);
+    if dir.exists() && !path.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir).map_err(Error::at(dir))?;
+    std::fs::write(&path, name).map_err(Error::at(&path))
+}
+
+/// Check whether `dir` is a build directory created by the driver called `name`, and if so, how
+/// long ago it was last used.
+fn marker_age(dir: &Utf8Path, name: &str) -> Option<Duration> {
+    let path = dir.join(MARKER);
+    if std::fs::read_to_string(&path).ok()? != name {
+        return None;
+    }
+    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+    Some(modified.elapsed().unwrap_or_default())
+}
+
+/// Remove a build directory for the driver called `name`, if it exists and has not been used for
+/// at least `min_age`. Refuse to remove directories without a marker. Return whether the
+/// directory was removed.
+pub(crate) fn clean_dir(dir: &Utf8Path, name: &str, min_age: Option<Duration>) -> Result<bool> {
+    if !dir.exists() {
+        return Ok(false);
+    }
+    let age = marker_age(dir, name).ok_or_else(|| Error::NotWorkdir(dir.to_path_buf()))?;
+    if min_age.is_some_and(|min| age < min) {
+        return Ok(false);
+    }
+    std::fs::remove_dir_all(dir).map_err(Error::at(dir))?;
+    Ok(true)
+}
+
+/// Remove the persistent build directories under the cache root that have not been used for at
+/// least `min_age`, skipping anything that isn't a build directory. Return the removed
+/// directories.
+pub(crate) fn clean_all(
+    root: &Utf8Path,
+    name: &str,
+    min_age: Option<Duration>,
+) -> Result<Vec<Utf8PathBuf>> {
+    let entries = match root.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(Error::at(root)(e)),
+    };
+    let mut removed = vec![];
+    for entry in entries {
+        let entry = entry.map_err(Error::at(root))?;
+        if marker_age(entry.path(), name).is_some() && clean_dir(entry.path(), name, min_age)? {
+            removed.push(entry.into_path());
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn joins_undo_leading_parents() {
+        let join = |dir: &str, path: &str| join(dir.into(), path.into()).to_string();
+        assert_eq!(join(".build", "../out.c"), "out.c");
+        assert_eq!(join(".build", "in.b"), ".build/in.b");
+        assert_eq!(join("a/b", "../../x/../y"), "y");
+        assert_eq!(join(".build", ".."), ".");
+        assert_eq!(join("..", "../x"), "../../x");
+        assert_eq!(join("/tmp/build", "/abs/in.a"), "/abs/in.a");
+    }
+
+    #[test]
+    fn only_marked_dirs_are_cleaned() {
+        let tmp = TempDir::new("workdir-clean");
+        let ours = tmp.0.join("ours");
+        let theirs = tmp.0.join("theirs");
+        let plain = tmp.0.join("plain");
+        create(&ours, "fake").unwrap();
+        create(&theirs, "other").unwrap();
+        std::fs::create_dir(&plain).unwrap();
+
+        // An existing directory without a marker stays unmarked.
+        create(&plain, "fake").unwrap();
+        assert!(!plain.join(MARKER).exists());
+        assert!(matches!(
+            clean_dir(&plain, "fake", None),
+            Err(Error::NotWorkdir(_))
+        ));
+        assert!(matches!(
+            clean_dir(&theirs, "fake", None),
+            Err(Error::NotWorkdir(_))
+        ));
+        assert!(!clean_dir(&tmp.0.join("missing"), "fake", None).unwrap());
+
+        // The marker was just written, so the directory is too new to clean by age.
+        let hour = Some(Duration::from_secs(60 * 60));
+        assert!(clean_all(&tmp.0, "fake", hour).unwrap().is_empty());
+        assert_eq!(clean_all(&tmp.0, "fake", None).unwrap(), [ours.as_path()]);
+        assert!(!ours.exists() && theirs.exists() && plain.exists());
+    }
+}
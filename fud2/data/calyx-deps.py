@@ -0,0 +1,42 @@
+"""Print a Makefile-style depfile listing the files that a Calyx program
+imports, directly or indirectly.
+
+Usage: calyx-deps.py TARGET SOURCE LIBDIR
+"""
+import os
+import re
+import sys
+
+IMPORT_RE = re.compile(r'\bimport\s+"([^"]+)"\s*;')
+
+
+def scan(path, libdir, found):
+    """Add the files imported by `path` to `found`, recursively. Like the
+    Calyx compiler, look for imports relative to the importing file first and
+    then in the library directory.
+    """
+    try:
+        with open(path) as f:
+            text = f.read()
+    except OSError:
+        return
+    for name in IMPORT_RE.findall(text):
+        for base in (os.path.dirname(path), libdir):
+            candidate = os.path.normpath(os.path.join(base, name))
+            if os.path.isfile(candidate):
+                if candidate not in found:
+                    found.add(candidate)
+                    scan(candidate, libdir, found)
+                break
+
+
+def main():
+    target, source, libdir = sys.argv[1:]
+    found = set()
+    scan(source, libdir, found)
+    deps = " ".join(p.replace(" ", "\\ ") for p in sorted(found))
+    print(f"{target}: {deps}")
+
+
+if __name__ == "__main__":
+    main()
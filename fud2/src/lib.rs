@@ -0,0 +1,498 @@
+use fake::{
+    cli,
+    driver::Plugin,
+    run::{EmitResult, Emitter},
+    DriverBuilder,
+};
+use std::process::ExitCode;
+
+/// A scratch file for the step that produces `output`. It goes in the build directory and is
+/// named after the output, so several steps in one plan don't clash.
+fn scratch_file(output: &str, suffix: &str) -> String {
+    let name = std::path::Path::new(output)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(output);
+    format!("{}.{}", name, suffix)
+}
+
+/// Emit the build commands to run the simulator `input`, producing `targets` (the first of which is
+/// the log). Each simulation converts its own copy of the input data into `datadir`.
+fn sim_run(
+    e: &mut Emitter,
+    input: &str,
+    targets: &[&str],
+    datadir: &str,
+    args: &str,
+) -> EmitResult {
+    e.build("hex-data", "$sim_data", datadir)?;
+    e.build_cmd(targets, "sim-run", &[input, datadir], &[])?;
+    e.arg("bin", input)?;
+    e.arg("datadir", datadir)?;
+    e.arg("args", args)?;
+    let cycle_limit = e.config_or("sim.cycle_limit", "500000000");
+    e.arg("cycle_limit", &cycle_limit)?;
+    Ok(())
+}
+
+/// Convert the input data for a Cider run that produces `output`, returning the data file.
+fn cider_data(e: &mut Emitter, output: &str) -> std::io::Result<String> {
+    let dir = scratch_file(output, "cider");
+    let data = format!("{}/data.json", dir);
+    e.build_cmd(&[&data], "dat-to-interp", &["$sim_data"], &[])?;
+    e.arg("out_dir", &dir)?;
+    Ok(data)
+}
+
+/// Check whether `needle` appears anywhere in `data`, for sniffing file contents.
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Register fud2's core states, operations, and setups.
+pub fn build_driver(bld: &mut DriverBuilder) {
+    // Calyx.
+    let calyx = bld.state("calyx", &["futil"]);
+    let verilog = bld.state("verilog", &["sv", "v"]);
+    bld.sniff(calyx, |data| {
+        contains(data, b"component ") && contains(data, b"cells")
+    });
+    bld.sniff(verilog, |data| contains(data, b"module "));
+    let calyx_setup = bld.setup("Calyx compiler", |e| {
+        e.config_var("calyx_base", "calyx.base")?;
+        e.config_var_or("calyx_exe", "calyx.exe", "$calyx_base/target/debug/calyx")?;
+        e.config_var_or("python", "python", "python3")?;
+
+        // Track the files that Calyx programs import, so editing them triggers a rebuild.
+        e.add_file("calyx-deps.py", include_bytes!("../data/calyx-deps.py"))?;
+        e.rule(
+            "calyx",
+            "$calyx_exe -l $calyx_base -b $backend $args $in > $out && $python calyx-deps.py $out $in $calyx_base > $out.d",
+        )?;
+        e.depfile("$out.d", Some("gcc"))?;
+        Ok(())
+    });
+    bld.op(
+        "calyx-to-verilog",
+        &[calyx_setup],
+        calyx,
+        verilog,
+        |e, input, output| {
+            e.build_cmd(&[output], "calyx", &[input], &[])?;
+            e.arg("backend", "verilog")?;
+            Ok(())
+        },
+    );
+
+    // Dahlia.
+    let dahlia = bld.state("dahlia", &["fuse"]);
+    let dahlia_setup = bld.setup("Dahlia compiler", |e| {
+        e.var("dahlia_exec", "/Users/asampson/cu/research/dahlia/fuse")?;
+        e.rule(
+            "dahlia-to-calyx",
+            "$dahlia_exec -b calyx --lower -l error $in -o $out",
+        )?;
+        Ok(())
+    });
+    bld.rule(&[dahlia_setup], dahlia, calyx, "dahlia-to-calyx");
+
+    // MrXL.
+    let mrxl = bld.state("mrxl", &["mrxl"]);
+    let mrxl_setup = bld.setup("MrXL compiler", |e| {
+        e.var("mrxl_exec", "mrxl")?;
+        e.rule("mrxl-to-calyx", "$mrxl_exec $in > $out")?;
+        Ok(())
+    });
+    bld.rule(&[mrxl_setup], mrxl, calyx, "mrxl-to-calyx");
+
+    // Shared machinery for RTL simulators.
+    let dat = bld.state("dat", &["json"]);
+    let vcd = bld.state("vcd", &["vcd"]);
+    bld.sniff(vcd, |data| data.trim_ascii_start().starts_with(b"$"));
+    let simulator = bld.state("sim", &["exe"]);
+    let sim_setup = bld.setup("RTL simulation", |e| {
+        // Data conversion to and from JSON.
+        e.config_var_or("python", "python", "python3")?;
+        e.var(
+            "json_dat",
+            &format!("$python {}/json-dat.py", e.config_val("data")?),
+        )?;
+        e.rule("hex-data", "$json_dat --from-json $in $out")?;
+        e.rule("json-data", "$json_dat --to-json $out $in")?;
+
+        // The Verilog testbench.
+        e.var("testbench", &format!("{}/tb.sv", e.config_val("data")?))?;
+
+        // Rule for simulation execution.
+        e.rule(
+            "sim-run",
+            "./$bin +DATA=$datadir +CYCLE_LIMIT=$cycle_limit $args > $out",
+        )?;
+
+        Ok(())
+    });
+    // The input data file is separate from the rest of the simulation setup, so compiling a
+    // simulator doesn't depend on it.
+    let sim_data_setup = bld.setup("simulation data", |e| {
+        // `sim.data` is required.
+        let data_name = e.config_val("sim.data")?;
+        let data_path = e.external_path(data_name.as_ref())?;
+        e.var("sim_data", data_path.as_str())?;
+        Ok(())
+    });
+    bld.op(
+        "simulate",
+        &[sim_setup, sim_data_setup],
+        simulator,
+        dat,
+        |e, input, output| {
+            let log = scratch_file(output, "log");
+            let datadir = scratch_file(output, "data");
+            sim_run(e, input, &[&log], &datadir, "+NOTRACE=1")?;
+            e.build_cmd(&[output], "json-data", &[&datadir, &log], &[])?;
+            Ok(())
+        },
+    );
+    bld.op(
+        "trace",
+        &[sim_setup, sim_data_setup],
+        simulator,
+        vcd,
+        |e, input, output| {
+            let log = scratch_file(output, "log");
+            let datadir = scratch_file(output, "data");
+            let args = format!("+NOTRACE=0 +OUT={}", output);
+            sim_run(e, input, &[&log, output], &datadir, &args)?;
+            Ok(())
+        },
+    );
+
+    // Icarus Verilog. The "noverify" state's extension is distinct from plain Verilog's so that an
+    // output named `foo.sv` is unambiguously the usual kind.
+    let verilog_noverify = bld.state("verilog-noverify", &["noverify.sv"]);
+    let icarus_setup = bld.setup("Icarus Verilog", |e| {
+        e.var("iverilog", "iverilog")?;
+        e.rule("icarus-compile", "$iverilog -g2012 -o $out $testbench $in")?;
+        Ok(())
+    });
+    bld.op(
+        "calyx-noverify",
+        &[calyx_setup],
+        calyx,
+        verilog_noverify,
+        |e, input, output| {
+            // Icarus requires a special --disable-verify version of Calyx code.
+            e.build_cmd(&[output], "calyx", &[input], &[])?;
+            e.arg("backend", "verilog")?;
+            e.arg("args", "--disable-verify")?;
+            Ok(())
+        },
+    );
+    bld.op(
+        "icarus",
+        &[sim_setup, icarus_setup],
+        verilog_noverify,
+        simulator,
+        |e, input, output| {
+            e.build("icarus-compile", input, output)?;
+            Ok(())
+        },
+    );
+
+    // Calyx to FIRRTL.
+    let firrtl = bld.state("firrtl", &["fir"]);
+    bld.sniff(firrtl, |data| {
+        let data = data.trim_ascii_start();
+        data.starts_with(b"circuit ") || data.starts_with(b"FIRRTL version")
+    });
+    bld.op(
+        "calyx-to-firrtl",
+        &[calyx_setup],
+        calyx,
+        firrtl,
+        |e, input, output| {
+            e.build_cmd(&[output], "calyx", &[input], &[])?;
+            e.arg("backend", "firrtl")?;
+            Ok(())
+        },
+    );
+
+    // The FIRRTL compiler.
+    let firrtl_setup = bld.setup("Firrtl to Verilog compiler", |e| {
+        e.config_var("firrtl_exe", "firrtl.exe")?;
+        e.rule("firrtl", "$firrtl_exe -i $in -o $out -X sverilog")?;
+
+        e.var(
+            "primitives-for-firrtl",
+            &format!("{}/primitives-for-firrtl.sv", e.config_val("data")?),
+        )?;
+        e.rule("add-firrtl-prims", "cat $primitives-for-firrtl $in > $out")?;
+
+        Ok(())
+    });
+    fn firrtl_compile(e: &mut Emitter, input: &str, output: &str) -> EmitResult {
+        let tmp_verilog = "partial.sv";
+        e.build_cmd(&[tmp_verilog], "firrtl", &[input], &[])?;
+        e.build_cmd(&[output], "add-firrtl-prims", &[tmp_verilog], &[])?;
+        Ok(())
+    }
+    bld.op("firrtl", &[firrtl_setup], firrtl, verilog, firrtl_compile);
+    // This is a bit of a hack, but the Icarus-friendly "noverify" state is identical for this path
+    // (since FIRRTL compilation doesn't come with verification).
+    bld.op(
+        "firrtl-noverify",
+        &[firrtl_setup],
+        firrtl,
+        verilog_noverify,
+        firrtl_compile,
+    );
+
+    // primitive-uses backend
+    let primitive_uses_json = bld.state("primitive-uses-json", &["prim.json"]);
+    bld.op(
+        "primitive-uses",
+        &[calyx_setup],
+        calyx,
+        primitive_uses_json,
+        |e, input, output| {
+            e.build_cmd(&[output], "calyx", &[input], &[])?;
+            e.arg("backend", "primitive-uses")?;
+            Ok(())
+        },
+    );
+
+    // Verilator.
+    let verilator_setup = bld.setup("Verilator", |e| {
+        e.config_var_or("verilator", "verilator.exe", "verilator")?;
+        e.rule(
+            "verilator-compile",
+            "$verilator $in $testbench --trace --binary --top-module TOP -fno-inline -Mdir $out_dir",
+        )?;
+        e.rule("cp", "cp $in $out")?;
+        Ok(())
+    });
+    bld.op(
+        "verilator",
+        &[sim_setup, verilator_setup],
+        verilog,
+        simulator,
+        |e, input, output| {
+            let out_dir = "verilator-out";
+            let sim_bin = format!("{}/VTOP", out_dir);
+            e.build("verilator-compile", input, &sim_bin)?;
+            e.arg("out_dir", out_dir)?;
+            e.build("cp", &sim_bin, output)?;
+            Ok(())
+        },
+    );
+
+    // Interpreter.
+    let debug = bld.state("debug", &[]); // A pseudo-state.
+    let cider_setup = bld.setup("Cider interpreter", |e| {
+        e.config_var_or("cider", "cider.exe", "$calyx_base/target/debug/cider")?;
+        e.rule(
+            "cider",
+            "$cider -l $calyx_base --raw --data $data $in > $out",
+        )?;
+        e.rule(
+            "cider-debug",
+            "$cider -l $calyx_base --data $data $in debug || true",
+        )?;
+        e.arg("pool", "console")?;
+
+        // TODO Can we reduce the duplication around `rsrc_dir` and `$python`?
+        let rsrc_dir = e.config_val("data")?;
+        e.var("interp-dat", &format!("{}/interp-dat.py", rsrc_dir))?;
+        e.config_var_or("python", "python", "python3")?;
+        e.rule(
+            "dat-to-interp",
+            "$python $interp-dat --to-interp $in $out_dir",
+        )?;
+        e.rule(
+            "interp-to-dat",
+            "$python $interp-dat --from-interp $in $sim_data > $out",
+        )?;
+        Ok(())
+    });
+    bld.op(
+        "interp",
+        &[sim_data_setup, calyx_setup, cider_setup],
+        calyx,
+        dat,
+        |e, input, output| {
+            let data = cider_data(e, output)?;
+            let out_file = scratch_file(output, "interp.json");
+            e.build_cmd(&[&out_file], "cider", &[input], &[&data])?;
+            e.arg("data", &data)?;
+            e.build_cmd(&[output], "interp-to-dat", &[&out_file], &["$sim_data"])?;
+            Ok(())
+        },
+    );
+    bld.op(
+        "debug",
+        &[sim_data_setup, calyx_setup, cider_setup],
+        calyx,
+        debug,
+        |e, input, output| {
+            let data = cider_data(e, output)?;
+            e.build_cmd(&[output], "cider-debug", &[input], &[&data])?;
+            e.arg("data", &data)?;
+            Ok(())
+        },
+    );
+
+    // Xilinx compilation.
+    let xo = bld.state("xo", &["xo"]);
+    let xclbin = bld.state("xclbin", &["xclbin"]);
+    let xilinx_setup = bld.setup("Xilinx tools", |e| {
+        // Locations for Vivado and Vitis installations.
+        e.config_var("vivado_dir", "xilinx.vivado")?;
+        e.config_var("vitis_dir", "xilinx.vitis")?;
+
+        // Package a Verilog program as an `.xo` file.
+        let rsrc_dir = e.config_val("data")?;
+        e.var("gen_xo_tcl", &format!("{}/gen_xo.tcl", rsrc_dir))?;
+        e.var("get_ports", &format!("{}/get-ports.py", rsrc_dir))?;
+        e.config_var_or("python", "python", "python3")?;
+        e.rule("gen-xo", "$vivado_dir/bin/vivado -mode batch -source $gen_xo_tcl -tclargs $out `$python $get_ports kernel.xml`")?;
+        e.arg("pool", "console")?;  // Lets Ninja stream the tool output "live."
+
+        // Compile an `.xo` file to an `.xclbin` file, which is where the actual EDA work occurs.
+        e.config_var_or("xilinx_mode", "xilinx.mode", "hw_emu")?;
+        e.config_var_or("platform", "xilinx.device", "xilinx_u50_gen3x16_xdma_201920_3")?;
+        e.rule("compile-xclbin", "$vitis_dir/bin/v++ -g -t $xilinx_mode --platform $platform --save-temps --profile.data all:all:all --profile.exec all:all:all -lo $out $in")?;
+        e.arg("pool", "console")?;
+
+        Ok(())
+    });
+    let xo_op = bld.op(
+        "xo",
+        &[calyx_setup, xilinx_setup],
+        calyx,
+        xo,
+        |e, input, output| {
+            // Emit the Verilog itself in "synthesis mode."
+            e.build_cmd(&["main.sv"], "calyx", &[input], &[])?;
+            e.arg("backend", "verilog")?;
+            e.arg("args", "--synthesis -p external")?;
+
+            // Extra ingredients for the `.xo` package.
+            e.build_cmd(&["toplevel.v"], "calyx", &[input], &[])?;
+            e.arg("backend", "xilinx")?;
+            e.build_cmd(&["kernel.xml"], "calyx", &[input], &[])?;
+            e.arg("backend", "xilinx-xml")?;
+
+            // Package the `.xo`.
+            e.build_cmd(
+                &[output],
+                "gen-xo",
+                &[],
+                &["main.sv", "toplevel.v", "kernel.xml"],
+            )?;
+            Ok(())
+        },
+    );
+    let xclbin_op = bld.op("xclbin", &[xilinx_setup], xo, xclbin, |e, input, output| {
+        e.build_cmd(&[output], "compile-xclbin", &[input], &[])?;
+        Ok(())
+    });
+
+    // The Xilinx tools are rarely installed, so only offer these operations when they're
+    // configured.
+    let xilinx_keys = ["xilinx.vivado", "xilinx.vitis"];
+    bld.require_config(xo_op, &xilinx_keys);
+    bld.require_config(xclbin_op, &xilinx_keys);
+
+    // Xilinx execution.
+    // TODO Only does `hw_emu` for now...
+    let xrt_setup = bld.setup("Xilinx execution via XRT", |e| {
+        // Generate `emconfig.json`.
+        e.rule("emconfig", "$vitis_dir/bin/emconfigutil --platform $platform")?;
+        e.build_cmd(&["emconfig.json"], "emconfig", &[], &[])?;
+
+        // Execute via the `xclrun` tool.
+        e.config_var("xrt_dir", "xilinx.xrt")?;
+        e.rule("xclrun", "bash -c 'source $vitis_dir/settings64.sh ; source $xrt_dir/setup.sh ; XRT_INI_PATH=$xrt_ini EMCONFIG_PATH=. XCL_EMULATION_MODE=$xilinx_mode $python -m fud.xclrun --out $out $in'")?;
+        e.arg("pool", "console")?;
+
+        // "Pre-sim" and "post-sim" scripts for simulation.
+        e.rule("echo", "echo $contents > $out")?;
+        e.build_cmd(&["pre_sim.tcl"], "echo", &[""], &[""])?;
+        e.arg("contents", "open_vcd\\\\nlog_vcd *\\\\n")?;
+        e.build_cmd(&["post_sim.tcl"], "echo", &[""], &[""])?;
+        e.arg("contents", "close_vcd\\\\n")?;
+
+        Ok(())
+    });
+    let xrt_op = bld.op(
+        "xrt",
+        &[xilinx_setup, sim_data_setup, xrt_setup],
+        xclbin,
+        dat,
+        |e, input, output| {
+            e.build_cmd(
+                &[output],
+                "xclrun",
+                &[input, "$sim_data"],
+                &["emconfig.json"],
+            )?;
+            let rsrc_dir = e.config_val("data")?;
+            e.arg("xrt_ini", &format!("{}/xrt.ini", rsrc_dir))?;
+            Ok(())
+        },
+    );
+    let xrt_trace_op = bld.op(
+        "xrt-trace",
+        &[xilinx_setup, sim_data_setup, xrt_setup],
+        xclbin,
+        vcd,
+        |e, input, output| {
+            e.build_cmd(
+                &[output], // TODO not the VCD, yet...
+                "xclrun",
+                &[input, "$sim_data"],
+                &["emconfig.json", "pre_sim.tcl", "post_sim.tcl"],
+            )?;
+            let rsrc_dir = e.config_val("data")?;
+            e.arg("xrt_ini", &format!("{}/xrt_trace.ini", rsrc_dir))?;
+            Ok(())
+        },
+    );
+    for op in [xrt_op, xrt_trace_op] {
+        bld.require_config(op, &xilinx_keys);
+        bld.require_config(op, &["xilinx.xrt"]);
+    }
+}
+
+/// Run fud2's command-line interface with some add-on plugins, each in its own namespace. An
+/// add-on crate can use this as its entire `main`.
+pub fn main_with(plugins: &[(&str, Plugin)]) -> ExitCode {
+    let mut bld = DriverBuilder::new("fud2");
+    bld.version(env!("CARGO_PKG_VERSION"));
+    build_driver(&mut bld);
+    for (namespace, plugin) in plugins {
+        bld.plugin(namespace, *plugin);
+    }
+    match fake::external::load_configured(&mut bld).and_then(|()| bld.build()) {
+        Ok(driver) => cli::cli(&driver),
+        Err(e) => {
+            cli::print_error(&e.into());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake::testing::{assert_path, assert_valid};
+
+    #[test]
+    fn driver_is_valid() {
+        let mut bld = DriverBuilder::new("fud2");
+        build_driver(&mut bld);
+        let driver = assert_valid(bld);
+        assert_path(&driver, "calyx", "verilog");
+        assert_path(&driver, "calyx", "dat");
+    }
+}